@@ -3,6 +3,7 @@ use super::*;
 use proton_kernel::kernel_process::KernelTask;
 use proton_kernel::arch::AbstractArch;
 use alloc::boxed::Box;
+use cortex_a::regs::*;
 
 pub struct AArch64;
 
@@ -18,4 +19,18 @@ impl AbstractArch for AArch64 {
     fn create_idle_task() -> Box<dyn KernelTask> {
         box crate::idle::Idle
     }
+
+    #[inline]
+    fn core_id() -> usize {
+        MPIDR_EL1.get() as usize & 0b11
+    }
+
+    fn start_secondary_cores() {
+        crate::start::start_secondary_cores()
+    }
+
+    /// The idle task waits in `wfe`
+    fn wake_idle_cores() {
+        unsafe { llvm_asm!("sev"); }
+    }
 }
@@ -0,0 +1,93 @@
+use proton::utils::volatile::Volatile;
+use crate::peripherals::*;
+
+/// GPU interrupts `0..64` (pending 1 and 2), then ARM interrupts `64..72` (basic pending)
+pub const IRQ_LINES: usize = 72;
+pub const GPU_IRQ_LINES: usize = 64;
+
+/// BCM2835 ARM interrupt controller, for the peripheral interrupts
+#[repr(C)]
+pub struct ArmInterruptRegisters {
+    pub basic_pending: Volatile<u32>,  // 0x00
+    pub pending: [Volatile<u32>; 2],   // 0x04
+    pub fiq_control: Volatile<u32>,    // 0x0c
+    pub enable: [Volatile<u32>; 2],    // 0x10
+    pub enable_basic: Volatile<u32>,   // 0x18
+    pub disable: [Volatile<u32>; 2],   // 0x1c
+    pub disable_basic: Volatile<u32>,  // 0x24
+}
+
+impl MemoryMappedRegisters for ArmInterruptRegisters {
+    const BASE: usize = PERIPHERAL_BASE + 0xB200;
+}
+
+impl ArmInterruptRegisters {
+    /// ARM interrupts in the basic pending register. Other bits are shortcuts to GPU interrupts.
+    pub const BASIC_ARM_MASK: u32 = 0xFF;
+
+    pub fn disable_all(&mut self) {
+        self.disable[0].set(!0);
+        self.disable[1].set(!0);
+        self.disable_basic.set(!0);
+        self.fiq_control.set(0);
+    }
+
+    /// Enable registers are write-1-to-set, so other interrupts are not affected
+    pub fn enable_irq(&mut self, irq: usize) {
+        debug_assert!(irq < IRQ_LINES);
+        if irq < GPU_IRQ_LINES {
+            self.enable[irq / 32].set(1 << (irq % 32));
+        } else {
+            self.enable_basic.set(1 << (irq - GPU_IRQ_LINES));
+        }
+    }
+
+    pub fn disable_irq(&mut self, irq: usize) {
+        debug_assert!(irq < IRQ_LINES);
+        if irq < GPU_IRQ_LINES {
+            self.disable[irq / 32].set(1 << (irq % 32));
+        } else {
+            self.disable_basic.set(1 << (irq - GPU_IRQ_LINES));
+        }
+    }
+
+    /// The lowest enabled interrupt that is pending
+    pub fn next_pending(&self) -> Option<usize> {
+        for bank in 0..2 {
+            let pending = self.pending[bank].get() & self.enable[bank].get();
+            if pending != 0 {
+                return Some(bank * 32 + pending.trailing_zeros() as usize);
+            }
+        }
+        let basic = self.basic_pending.get() & self.enable_basic.get() & Self::BASIC_ARM_MASK;
+        if basic != 0 {
+            return Some(GPU_IRQ_LINES + basic.trailing_zeros() as usize);
+        }
+        None
+    }
+}
+
+/// QA7 local interrupt controller of the BCM2836/2837, for the per-core interrupts
+#[repr(C)]
+pub struct LocalInterruptRegisters {
+    pub control: Volatile<u32>,                         // 0x00
+    _0: [u8; 8],                                        // 0x04
+    pub gpu_interrupt_routing: Volatile<u32>,           // 0x0c
+    _1: [u8; 0x30],                                     // 0x10
+    pub timer_interrupt_control: [Volatile<u32>; 4],    // 0x40
+    pub mailbox_interrupt_control: [Volatile<u32>; 4],  // 0x50
+    pub irq_source: [Volatile<u32>; 4],                 // 0x60
+    pub fiq_source: [Volatile<u32>; 4],                 // 0x70
+}
+
+impl MemoryMappedRegisters for LocalInterruptRegisters {
+    const BASE: usize = ARM_TIMER_BASE;
+}
+
+impl LocalInterruptRegisters {
+    /// Non-secure physical timer, used by the kernel
+    pub const SOURCE_CNTPNS: u32 = 1 << 1;
+    /// Peripheral interrupts, only raised on the core in `gpu_interrupt_routing`
+    pub const SOURCE_GPU: u32 = 1 << 8;
+    pub const GPU_ROUTING_CORE0: u32 = 0;
+}
@@ -1,8 +1,17 @@
 use proton_kernel::arch::AbstractBootImage;
+use proton_kernel::privilege::Privileges;
+use proton::kernel_call::KernelCall;
+use proton::task::Priority;
+use proton::memory::*;
+use crate::peripherals::{PERIPHERAL_BASE, EMMC_IRQ};
 
 
 static INIT_ELF: &'static [u8] = include_bytes!("../../../target/aarch64-proton/init");
 static EMMC_ELF: &'static [u8] = include_bytes!("../../../target/aarch64-proton/emmc");
+static CMDLINE: &'static [u8] = include_bytes!("../cmdline");
+
+/// Physical address of the peripherals
+const PERIPHERAL_BASE_PHYSICAL: usize = PERIPHERAL_BASE & 0x0000ffff_ffffffff;
 
 pub struct BootImage;
 
@@ -11,7 +20,24 @@ impl AbstractBootImage for BootImage {
         match file {
             "init" => Some(INIT_ELF),
             "emmc" => Some(EMMC_ELF),
+            "cmdline" => Some(CMDLINE),
             _ => None,
         }
     }
+
+    fn privileges(file: &str) -> Privileges {
+        match file {
+            "init" => Privileges::user()
+                .allow_kernel_call(KernelCall::SetExceptionHandler)
+                .allow_kernel_call(KernelCall::TakeExceptionReport),
+            // GPIO and EMMC registers, and the EMMC interrupt only. Preempts compute-bound user programs.
+            "emmc" => Privileges::user()
+                .allow_physical_memory(Address::new(PERIPHERAL_BASE_PHYSICAL + 0x200000), Size4K::SIZE)
+                .allow_physical_memory(Address::new(PERIPHERAL_BASE_PHYSICAL + 0x300000), Size4K::SIZE)
+                .allow_irq(EMMC_IRQ)
+                .allow_priority(Priority::HIGH)
+                .restrict_send_to(&[]),
+            _ => Privileges::user(),
+        }
+    }
 }
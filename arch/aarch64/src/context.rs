@@ -8,61 +8,91 @@ use super::mm::page_table::PageFlags;
 use super::exception::ExceptionFrame;
 use cortex_a::regs::*;
 use proton::task::Message;
+use proton::ipc::IpcError;
+use proton_kernel::memory::write_to_user;
+use proton_kernel::task::Task;
 use proton_kernel::arch::*;
+use proton_kernel::AbstractKernel;
+use crate::Kernel;
 // use 
 
-#[repr(C, align(4096))]
+/// Kernel stacks are placed in 64K aligned blocks of physical memory
+#[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct Size64K;
+
+impl PageSize for Size64K {
+    const NAME: &'static str = "64K";
+    const LOG_SIZE: usize = 16;
+}
+
+// `el1_handler` in exception.rs detects stack overflow by checking the lower bits of SP.
+// It relies on a 4K guard page at the start of a 64K aligned block, followed by the stack.
+const _: () = [()][(Size4K::SIZE + KERNEL_STACK_SIZE > Size64K::SIZE) as usize];
+
+/// A kernel stack, mapped at `0xffff0000_00000000 | physical address`.
+///
+/// The guard page at the bottom of the block is left unmapped to trap stack overflow,
+/// and the frames above the stack are returned to the frame allocator.
 pub struct KernelStack {
-    /// This page is protected to trap stack overflow
-    guard: [u8; Size4K::SIZE],
-    stack: [u8; KERNEL_STACK_SIZE],
+    block: Frame<Size64K>,
 }
 
 impl KernelStack {
     pub fn new() -> Box<Self> {
-        let mut kernel_stack = unsafe { Box::<KernelStack>::new_uninit().assume_init() };
+        let kernel_stack = Self { block: FRAME_ALLOCATOR.alloc::<Size64K>() };
         kernel_stack.init();
-        kernel_stack
+        box kernel_stack
     }
-    pub fn init(&mut self) {
-        // let guard_page = Page::<Size4K, V>::new(Address::from(&self.guard as *const [u8; Size4K::SIZE]));
-        // PageTable::<L4>::get(true).update_flags(guard_page, PageFlags::_KERNEL_STACK_GUARD_FLAGS);
-        // let stack_page_start = Page::<Size4K, V>::new(Address::from(&self.stack as *const [u8; KERNEL_STACK_SIZE]));
-        // let stack_page_end = stack_page_start.add_usize(KERNEL_STACK_PAGES).unwrap();
-        // for stack_page in stack_page_start..stack_page_end {
-        //     PageTable::<L4>::get(true).update_flags(stack_page, PageFlags::_KERNEL_STACK_FLAGS);
-        // }
+    fn frame(&self, i: usize) -> Frame<Size4K> {
+        Frame::new(self.block.start() + i * Size4K::SIZE)
+    }
+    fn page(&self, i: usize) -> Page<Size4K, V> {
+        Page::new(Address::new(self.frame(i).start().as_usize() | 0xffff0000_00000000))
+    }
+    fn stack_frames(&self) -> ::core::ops::Range<usize> {
+        1..1 + KERNEL_STACK_PAGES
+    }
+    fn init(&self) {
+        Kernel::critical_section(|| {
+            // Any access to the guard page raises a translation fault
+            FRAME_ALLOCATOR.free(self.frame(0));
+            for i in self.stack_frames() {
+                PageTable::<L4>::get(true).map(self.page(i), self.frame(i), PageFlags::_KERNEL_STACK_FLAGS);
+            }
+            for i in self.stack_frames().end..Size64K::SIZE / Size4K::SIZE {
+                FRAME_ALLOCATOR.free(self.frame(i));
+            }
+            super::mm::paging::invalidate_tlb();
+        });
         for i in 0..KERNEL_STACK_SIZE {
             unsafe {
-                ::core::intrinsics::volatile_store(&mut self.stack[i], 0);
+                ::core::intrinsics::volatile_store((self.start_address() + i).as_ptr_mut::<u8>(), 0);
             }
         }
     }
     pub fn start_address(&self) -> Address {
-        let stack_start = Address::from(&self.stack as *const [u8; KERNEL_STACK_SIZE]);
-        stack_start
+        self.page(self.stack_frames().start).start()
     }
     pub fn end_address(&self) -> Address {
-        let stack_start = Address::from(&self.stack as *const [u8; KERNEL_STACK_SIZE]);
-        stack_start + KERNEL_STACK_SIZE
+        self.start_address() + KERNEL_STACK_SIZE
     }
     pub fn copy_from(&mut self, other: &Self) {
-        for i in 0..KERNEL_STACK_SIZE {
-            self.stack[i] = other.stack[i];
+        unsafe {
+            ::core::ptr::copy_nonoverlapping(other.start_address().as_ptr::<u8>(), self.start_address().as_ptr_mut::<u8>(), KERNEL_STACK_SIZE);
         }
     }
 }
 
 impl Drop for KernelStack {
-    // Unprotect stack pages
+    /// Unmap and release the stack frames
     fn drop(&mut self) {
-        let guard_page = Page::<Size4K, V>::new(Address::from(&self.guard as *const [u8; Size4K::SIZE]));
-        PageTable::<L4>::get(true).update_flags(guard_page, PageFlags::_KERNEL_DATA_FLAGS_4K);
-        let stack_page_start = Page::<Size4K, V>::new(Address::from(&self.stack as *const [u8; KERNEL_STACK_SIZE]));
-        let stack_page_end = ::core::iter::Step::forward(stack_page_start, KERNEL_STACK_PAGES);
-        for stack_page in stack_page_start..stack_page_end {
-            PageTable::<L4>::get(true).update_flags(stack_page, PageFlags::_KERNEL_DATA_FLAGS_4K);
-        }
+        Kernel::critical_section(|| {
+            for i in self.stack_frames() {
+                PageTable::<L4>::get(true).unmap(self.page(i));
+                FRAME_ALLOCATOR.free(self.frame(i));
+            }
+            super::mm::paging::invalidate_tlb();
+        });
     }
 }
 
@@ -95,6 +125,14 @@ pub struct Context {
     response_status: Option<isize>,
 }
 
+impl Context {
+    /// Whether the exception frame is taken from EL0
+    fn exception_frame_is_user(&self, frame: *const ExceptionFrame) -> bool {
+        // SPSR_EL1.M[3:0] is zero for EL0t
+        unsafe { (*frame).spsr_el1 & 0b1111 == 0 }
+    }
+}
+
 impl AbstractContext for Context {
     fn empty() -> Self {
         unsafe { ::core::mem::zeroed() }
@@ -104,7 +142,7 @@ impl AbstractContext for Context {
     /// and current p4 table
     fn new(entry: *const extern fn(a: *mut ()) -> !, ctx_ptr: *mut ()) -> Self {
         // Alloc page table
-        let p4 = unsafe {
+        let p4 = Kernel::critical_section(|| unsafe {
             let p4_frame = FRAME_ALLOCATOR.alloc::<Size4K>();
             let p4_page = super::mm::page_table::map_kernel_temporarily(p4_frame, PageFlags::_PAGE_TABLE_FLAGS, None);
             let p4 = p4_page.start().as_ref_mut::<PageTable<L4>>();
@@ -113,7 +151,7 @@ impl AbstractContext for Context {
             }
             p4.entries[511].set(p4_frame, PageFlags::_PAGE_TABLE_FLAGS);
             p4_frame
-        };
+        });
         // Alloc kernel stack
         let kernel_stack = KernelStack::new();
         let sp: *mut u8 = kernel_stack.end_address().as_ptr_mut();
@@ -151,28 +189,35 @@ impl AbstractContext for Context {
         // ctx
     }
  
-    // fn fork(&self) -> Self {
-    //     let mut ctx = Context {
-    //         exception_frame: 0usize as _,
-    //         entry_pc: 0usize as _, // x30
-    //         p4: self.p4,
-    //         kernel_stack: Some({
-    //             let mut kernel_stack = KernelStack::new();
-    //             kernel_stack.copy_from(self.kernel_stack.as_ref().unwrap());
-    //             kernel_stack
-    //         }),
-    //         kernel_stack_top: 0usize as _,
-    //         response_message: None,
-    //         response_status: None,
-    //     };
-    //     ctx.exception_frame = {
-    //         println!("Fork, sp = {:?}, kstack = {:?}", self.exception_frame, self.kernel_stack.as_ref().unwrap().start_address());
-    //         let sp_offset = self.exception_frame as usize - self.kernel_stack.as_ref().unwrap().start_address().as_usize();
-    //         (ctx.kernel_stack.as_ref().unwrap().start_address() + sp_offset).as_ptr_mut()
-    //     };
-    //     ctx.p4 = super::mm::paging::fork_page_table(self.p4);
-    //     ctx
-    // }
+    /// Duplicate this context. The forked task resumes from the same exception frame,
+    /// on a copy of the kernel stack and a copy-on-write clone of the address space.
+    fn fork(&self) -> Self {
+        debug_assert!(self.exception_frame as usize != 0);
+        let parent_kernel_stack = self.kernel_stack.as_ref().unwrap();
+        let kernel_stack = {
+            let mut kernel_stack = KernelStack::new();
+            kernel_stack.copy_from(parent_kernel_stack);
+            kernel_stack
+        };
+        let mut ctx = Self::empty();
+        ctx.entry_pc = self.entry_pc;
+        ctx.exception_frame = {
+            let sp_offset = self.exception_frame as usize - parent_kernel_stack.start_address().as_usize();
+            (kernel_stack.start_address() + sp_offset).as_ptr_mut()
+        };
+        ctx.kernel_stack_top = kernel_stack.end_address().as_ptr_mut();
+        ctx.kernel_stack = Some(kernel_stack);
+        ctx.p4 = super::mm::paging::fork_page_table(self.p4);
+        ctx
+    }
+
+    fn restart_in_kernel(&mut self, entry: *const extern fn(a: *mut ()) -> !, ctx_ptr: *mut ()) {
+        // A new exception frame will be created at the top of the kernel stack
+        self.exception_frame = 0usize as _;
+        self.entry_pc = entry as _;
+        self.response_message = None;
+        self.set_response_status(unsafe { ::core::mem::transmute(ctx_ptr) });
+    }
 
     fn set_response_message(&mut self, m: Message) {
         self.response_message = Some(m);
@@ -182,6 +227,10 @@ impl AbstractContext for Context {
         self.response_status = Some(s);
     }
 
+    fn is_user_mode(&self) -> bool {
+        !self.exception_frame.is_null() && self.exception_frame_is_user(self.exception_frame)
+    }
+
     unsafe extern fn return_to_user(&mut self) -> ! {
         assert!(!<AArch64 as AbstractArch>::Interrupt::is_enabled());
         // Switch page table
@@ -196,8 +245,9 @@ impl AbstractContext for Context {
             }
         }
         
+        let from_exception = self.exception_frame as usize != 0;
         let exception_frame = {
-            if self.exception_frame as usize == 0 {
+            if !from_exception {
                 let mut frame: *mut ExceptionFrame = (self.kernel_stack_top as usize - ::core::mem::size_of::<ExceptionFrame>()) as _;
                 (*frame).elr_el1 = self.entry_pc as _;
                 (*frame).spsr_el1 = 0b0101;
@@ -209,15 +259,20 @@ impl AbstractContext for Context {
                 p
             }
         };
-        // if let Some(msg) = self.response_message.take() {
-        //     let slot = Address::from((*exception_frame).x2 as *mut Message);
-        //     if slot.as_usize() & 0xffff_0000_0000_0000 == 0 {
-        //         if super::mm::is_copy_on_write_address(slot) {
-        //             super::mm::fix_copy_on_write_address(slot);
-        //         }
-        //     }
-        //     ::core::ptr::write(slot.as_ptr_mut(), msg);
-        // }
+        // Deliver the received message to the buffer in x2, when returning from an IPC call
+        if let Some(msg) = self.response_message.take().filter(|_| from_exception) {
+            if self.exception_frame_is_user(exception_frame) {
+                // The buffer may be demand-paged or copy-on-write (e.g. in a forked child).
+                // It was already checked when the IPC started, so this only fails if the buffer was unmapped since.
+                let task = Task::<Kernel>::current().unwrap().id();
+                if write_to_user::<Kernel, Message>(task, (*exception_frame).x2.into(), &msg).is_err() {
+                    self.response_status = Some(IpcError::InvalidArgument.status());
+                }
+            } else if (*exception_frame).x2 != 0 {
+                // Kernel tasks receive into kernel memory
+                ::core::ptr::write((*exception_frame).x2 as *mut Message, msg);
+            }
+        }
         if let Some(status) = self.response_status {
             // let slot = Address::from(&(*exception_frame).x0 as *const usize);
             // if slot.as_usize() & 0xffff_0000_0000_0000 == 0 {
@@ -254,8 +309,15 @@ impl AbstractContext for Context {
 }
 
 impl Drop for Context {
+    /// Release the user address space. The kernel stack is freed with its box.
     fn drop(&mut self) {
-        // println!("Context drop");
+        if self.p4.start().is_zero() {
+            return;
+        }
+        debug_assert!(self.p4.start().as_usize() as u64 != TTBR0_EL1.get());
+        Kernel::critical_section(|| {
+            super::mm::paging::release_page_table(self.p4);
+        });
     }
 }
 
@@ -2,7 +2,13 @@
 use super::gic::*;
 use proton_kernel::task::Task;
 use proton_kernel::arch::*;
+use proton::kernel_call::ExitReason;
 use crate::*;
+use proton::esr::*;
+#[cfg(feature="device-raspi3-qemu")]
+use super::bcm2835::*;
+#[cfg(feature="device-raspi3-qemu")]
+use crate::peripherals::MemoryMappedRegisters;
 #[cfg(feature="device-raspi4")]
 use core::intrinsics::{volatile_load, volatile_store};
 
@@ -26,14 +32,6 @@ pub enum ExceptionKind {
     SError = 3,
 }
 
-#[repr(u32)]
-#[derive(Debug)]
-pub enum ExceptionClass {
-    SVCAArch64 = 0b010101,
-    DataAbortLowerEL = 0b100100,
-    DataAbortHigherEL = 0b100101,
-}
-
 #[repr(C)]
 #[derive(Debug)]
 pub struct ExceptionFrame {
@@ -53,139 +51,173 @@ pub struct ExceptionFrame {
     pub x1: usize,
 }
 
-unsafe fn get_exception_class() -> ExceptionClass {
+impl ExceptionFrame {
+    /// Whether the exception is taken from EL0
+    #[inline]
+    pub fn is_from_user(&self) -> bool {
+        self.spsr_el1 & 0b1111 == 0
+    }
+}
+
+unsafe fn get_esr() -> Esr {
     let esr_el1: u32;
     llvm_asm!("mrs $0, esr_el1":"=r"(esr_el1));
-    ::core::mem::transmute(esr_el1 >> 26)
+    Esr::new(esr_el1)
 }
 
-#[no_mangle]
-pub unsafe extern fn handle_exception(exception_frame: *mut ExceptionFrame) {
-    // println!("EF = {:?}", exception_frame as *mut _);
-    debug_assert!(Task::<Kernel>::current().unwrap().context.exception_frame as usize == 0);
-    Task::<Kernel>::current().map(|t| t.context.exception_frame = exception_frame);
-    let exception = get_exception_class();
-    debug!(Kernel: "Exception received");
-    match exception {
-        ExceptionClass::SVCAArch64 => {
-            debug!(Kernel: "SVCAArch64 Start {:?}", Task::<Kernel>::current().unwrap().id());
-            let _r = super::interrupt::handle_interrupt(InterruptId::Soft, &mut *exception_frame);
-            ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
-            // unsafe { (*exception_frame).x0 = ::core::mem::transmute(r) };
-            debug!(Kernel: "SVCAArch64 End {:?}", Task::<Kernel>::current().unwrap().id());
-        },
-        ExceptionClass::DataAbortLowerEL | ExceptionClass::DataAbortHigherEL => {
-            let far: usize;
-            llvm_asm!("mrs $0, far_el1":"=r"(far));
-            let elr: usize;
-            llvm_asm!("mrs $0, elr_el1":"=r"(elr));
-            debug!(Kernel: "Data Abort {:?} {:?}", far as *mut (), elr as *mut ());
-            // debug!(Kernel: "Data Abort {:?}", far as *mut ());
-            super::mm::handle_user_pagefault(far.into());
-        },
-        #[allow(unreachable_patterns)]
-        v => {
-            debug!(Kernel: "Exception Frame: {:?} {:?}", exception_frame, *exception_frame);
-            let esr_el1: u32;
-            llvm_asm!("mrs $0, esr_el1":"=r"(esr_el1));
-            debug!(Kernel: "ESR_EL1 = <EC={:x}, IL={:x}>", esr_el1 >> 26, esr_el1 & ((1 << 26) - 1));
-            // debug!(Kernel: "0x212ba4 -> 0x{:x}", *(0x212ba4usize as *const usize));
-            panic!("Unknown exception 0b{:b}", ::core::mem::transmute::<_, u32>(v))
-        },
+unsafe fn get_far() -> usize {
+    let far: usize;
+    llvm_asm!("mrs $0, far_el1":"=r"(far));
+    far
+}
+
+/// Kill the current task if the exception is from EL0, otherwise panic with a report
+unsafe fn unhandled_exception(exception_frame: *mut ExceptionFrame, esr: Esr) -> ! {
+    let pc: usize = (*exception_frame).elr_el1;
+    if (*exception_frame).is_from_user() {
+        let reason = if esr.is_abort() && esr.is_far_valid() {
+            ExitReason::PageFault { address: get_far().into(), pc: pc.into() }
+        } else {
+            ExitReason::Exception { syndrome: esr.0 as usize, pc: pc.into() }
+        };
+        Task::<Kernel>::abort_current(reason)
     }
-    
-    ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
-    
-    debug!(Kernel: "return_to_use00");
-    Task::<Kernel>::current().unwrap().context.return_to_user();
+    debug!(Kernel: "Exception Frame: {:?} {:?}", exception_frame, *exception_frame);
+    panic!(
+        "Unhandled kernel exception in task {:?}: {:?}, pc = {:?}, far = {:?}",
+        Task::<Kernel>::current().map(|t| t.id()), esr, pc as *mut (), get_far() as *mut ()
+    )
 }
 
-#[no_mangle]
-pub unsafe extern fn handle_exception_serror(exception_frame: *mut ExceptionFrame) {
-    // println!("EF = {:?}", exception_frame as *mut _);
-    debug_assert!(Task::<Kernel>::current().unwrap().context.exception_frame as usize == 0);
-    Task::<Kernel>::current().map(|t| t.context.exception_frame = exception_frame);
-    let exception = get_exception_class();
-    debug!(Kernel: "SError received {:?}", exception);
-    match exception {
+unsafe fn handle_synchronous_exception(exception_frame: *mut ExceptionFrame) {
+    let esr = get_esr();
+    debug!(Kernel: "Exception received {:?}", esr);
+    match esr.class() {
         ExceptionClass::SVCAArch64 => {
             debug!(Kernel: "SVCAArch64 Start {:?}", Task::<Kernel>::current().unwrap().id());
             let _r = super::interrupt::handle_interrupt(InterruptId::Soft, &mut *exception_frame);
             ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
-            // unsafe { (*exception_frame).x0 = ::core::mem::transmute(r) };
             debug!(Kernel: "SVCAArch64 End {:?}", Task::<Kernel>::current().unwrap().id());
         },
-        ExceptionClass::DataAbortLowerEL | ExceptionClass::DataAbortHigherEL => {
-            let far: usize;
-            llvm_asm!("mrs $0, far_el1":"=r"(far));
-            let elr: usize;
-            llvm_asm!("mrs $0, elr_el1":"=r"(elr));
-            debug!(Kernel: "Data Abort {:?} {:?}", far as *mut (), elr as *mut ());
-            // debug!(Kernel: "Data Abort {:?}", far as *mut ());
-            super::mm::handle_user_pagefault(far.into());
+        class if class.is_instruction_abort() || class.is_data_abort() => {
+            let far = get_far();
+            debug!(Kernel: "Abort {:?} {:?}", far as *mut (), (*exception_frame).elr_el1 as *mut ());
+            // Only translation, access and permission faults can be fixed by the page fault handler
+            let resolvable = esr.is_far_valid() && esr.fault_status().map(|s| s.is_paging_fault()).unwrap_or(false);
+            if !resolvable || super::mm::handle_user_pagefault(far.into()).is_err() {
+                unhandled_exception(exception_frame, esr);
+            }
         },
-        #[allow(unreachable_patterns)]
-        v => {
-            debug!(Kernel: "Exception Frame: {:?} {:?}", exception_frame, *exception_frame);
-            let esr_el1: u32;
-            llvm_asm!("mrs $0, esr_el1":"=r"(esr_el1));
-            debug!(Kernel: "ESR_EL1 = <EC={:x}, IL={:x}>", esr_el1 >> 26, esr_el1 & ((1 << 26) - 1));
-            // debug!(Kernel: "0x212ba4 -> 0x{:x}", *(0x212ba4usize as *const usize));
-            panic!("Unknown exception 0b{:b}", ::core::mem::transmute::<_, u32>(v))
+        _ => unhandled_exception(exception_frame, esr),
+    }
+}
+
+/// SError is asynchronous, so it can't always be blamed on the current task
+unsafe fn handle_serror(exception_frame: *mut ExceptionFrame) {
+    let esr = get_esr();
+    let severity = esr.serror_severity().unwrap_or(SErrorSeverity::Unknown);
+    debug!(Kernel: "SError received {:?}", esr);
+    match severity {
+        SErrorSeverity::Corrected => {},
+        SErrorSeverity::Restartable | SErrorSeverity::Recoverable if (*exception_frame).is_from_user() => {
+            // The error is contained in the current user task
+            Task::<Kernel>::abort_current(ExitReason::Exception { syndrome: esr.0 as usize, pc: (*exception_frame).elr_el1.into() })
         },
+        _ => panic!(
+            "SError in task {:?}: {:?}, pc = {:?}",
+            Task::<Kernel>::current().map(|t| t.id()), esr, (*exception_frame).elr_el1 as *mut ()
+        ),
     }
-    
-    ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
-    
-    debug!(Kernel: "return_to_use00");
-    Task::<Kernel>::current().unwrap().context.return_to_user();
+}
+
+/// No FIQ source is configured
+unsafe fn handle_fiq(exception_frame: *mut ExceptionFrame) {
+    panic!(
+        "Unexpected FIQ in task {:?}, pc = {:?}",
+        Task::<Kernel>::current().map(|t| t.id()), (*exception_frame).elr_el1 as *mut ()
+    )
 }
 
 #[cfg(feature="device-raspi4")]
-#[no_mangle]
-pub extern fn handle_interrupt(exception_frame: &mut ExceptionFrame) {
-    Task::<Kernel>::current().unwrap().context.exception_frame = exception_frame;
-    ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
+unsafe fn handle_irq(exception_frame: *mut ExceptionFrame) {
     #[allow(non_snake_case)]
     let GICC = GICC::get();
-    let iar = unsafe { volatile_load(&GICC.IAR) };
+    let iar = volatile_load(&GICC.IAR);
     let irq = iar & GICC::IAR_INTERRUPT_ID__MASK;
-    unsafe { volatile_store(&mut GICC.EOIR, iar) }; // FIXME: End of Interrupt ??? here ???
+    volatile_store(&mut GICC.EOIR, iar); // FIXME: End of Interrupt ??? here ???
     if irq < 256 {
         if irq == 30 {
-            unsafe { volatile_store(&mut GICC.EOIR, iar) };
             super::interrupt::handle_interrupt(InterruptId::Timer, &mut *exception_frame);
-            return;
         } else {
-            panic!("Unknown IRQ");
+            // Device interrupts are masked and forwarded to the subscribed driver
+            Kernel::global().irq.handle_irq(irq as usize);
         }
     }
-    
-    ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
-    unsafe {
-        // debug!(Kernel: "return_to_use00");
-        Task::<Kernel>::current().unwrap().context.return_to_user();
-    }
 }
 
 #[cfg(feature="device-raspi3-qemu")]
+unsafe fn handle_irq(exception_frame: *mut ExceptionFrame) {
+    let source = LocalInterruptRegisters::get().irq_source[AArch64::core_id()].get();
+    if source & !(LocalInterruptRegisters::SOURCE_CNTPNS | LocalInterruptRegisters::SOURCE_GPU) != 0 {
+        panic!("Unknown IRQ");
+    }
+    // Device interrupts go first, since the timer tick doesn't return (it ends in the scheduler)
+    if source & LocalInterruptRegisters::SOURCE_GPU != 0 {
+        // Device interrupts are masked and forwarded to the subscribed driver.
+        // Masked interrupts are no longer pending, so this terminates.
+        while let Some(irq) = ArmInterruptRegisters::get().next_pending() {
+            Kernel::global().irq.handle_irq(irq);
+        }
+    }
+    if source & LocalInterruptRegisters::SOURCE_CNTPNS != 0 {
+        super::interrupt::handle_interrupt(InterruptId::Timer, &mut *exception_frame);
+    }
+}
+
+const EMERGENCY_STACK_SIZE: usize = 1 << 14;
+
+#[repr(C, align(16))]
+pub struct EmergencyStacks([[u8; EMERGENCY_STACK_SIZE]; 4]);
+
+/// Per-core stacks, used for reporting kernel stack overflow
 #[no_mangle]
-pub extern fn handle_interrupt(exception_frame: &mut ExceptionFrame) {
-    // println!("EF = {:?}", exception_frame as *mut _);
-    // debug!(Kernel: "Interrupt received {:?}", exception_frame);
-    
-    debug_assert!(Task::<Kernel>::current().unwrap().context.exception_frame as usize == 0);
-    Task::<Kernel>::current().unwrap().context.exception_frame = exception_frame;
+pub static mut EMERGENCY_STACKS: EmergencyStacks = EmergencyStacks([[0; EMERGENCY_STACK_SIZE]; 4]);
 
-    if super::timer::pending_timer_irq() {
-        super::interrupt::handle_interrupt(InterruptId::Timer, exception_frame);
-    } else {
-        // println!(AArch64: "Unknown IRQ");
-        loop {}
+/// Called on the emergency stack, when an EL1 exception is taken
+/// with less than 4K of kernel stack left
+#[no_mangle]
+pub unsafe extern fn handle_kernel_stack_overflow(sp: usize) -> ! {
+    let esr = get_esr();
+    let far = get_far();
+    match Task::<Kernel>::current() {
+        Some(task) => panic!("kernel stack overflow in task {}: sp = {:?}, far = {:?}, {:?}", task.id().0, sp as *mut (), far as *mut (), esr),
+        None => panic!("kernel stack overflow: sp = {:?}, far = {:?}, {:?}", sp as *mut (), far as *mut (), esr),
     }
-    unsafe {
-        Task::<Kernel>::current().unwrap().context.return_to_user();
+}
+
+/// The entry of all exception vectors
+#[no_mangle]
+pub unsafe extern fn handle_exception(exception_frame: *mut ExceptionFrame, kind: ExceptionKind) -> ! {
+    // Released in `exit_exception`
+    Kernel::global().kernel_lock.acquire(AArch64::core_id());
+    debug_assert!(Task::<Kernel>::current().unwrap().context.exception_frame as usize == 0);
+    Task::<Kernel>::current().map(|t| t.context.exception_frame = exception_frame);
+    ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
+    match kind {
+        ExceptionKind::Synchronous => handle_synchronous_exception(exception_frame),
+        ExceptionKind::IRQ => handle_irq(exception_frame),
+        ExceptionKind::FIQ => handle_fiq(exception_frame),
+        ExceptionKind::SError => handle_serror(exception_frame),
     }
+    ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
+    Task::<Kernel>::current().unwrap().context.return_to_user();
+}
+
+/// Called before returning from an exception, when SP already points to the next task's exception frame.
+/// From here on, the previous task can be picked up by other cores.
+#[no_mangle]
+pub extern fn release_kernel_lock() {
+    Kernel::global().kernel_lock.release_all(AArch64::core_id());
 }
 
 extern {
@@ -194,9 +226,13 @@ extern {
 }
 
 
-// FIXME: We may need to switch stack after enter an exception,
-//        to avoid stack overflow.
 // Exception handlers table
+//
+// Exceptions taken from EL1 check for kernel stack overflow before pushing anything.
+// Task kernel stacks are 64K aligned, with a guard page at the bottom (see `KernelStack` in context.rs).
+// Less than 4K of stack left means the stack has overflowed (or will overflow),
+// so we switch to the per-core emergency stack to report it.
+// The boot stacks are not 64K aligned, so the check is skipped on them.
 global_asm! {"
 .global exception_handlers
 .global exit_exception
@@ -286,47 +322,93 @@ global_asm! {"
 .endm
 
 exit_exception:
+    bl release_kernel_lock
     pop_all
     eret
 
-except:
+.macro handler, kind
     push_all
     mov x0, sp
+    mov x1, #\\kind
     bl handle_exception
     except_hang 0
+.endm
 
-serror:
-    push_all
-    mov x0, sp
-    bl handle_exception_serror
-    except_hang 0
+except_sync:
+    handler 0
 
-irq:
-    push_all
-    mov x0, sp
-    bl handle_interrupt
+except_irq:
+    handler 1
+
+except_fiq:
+    handler 2
+
+except_serror:
+    handler 3
+
+.macro el1_handler, kind
+    // Swap x0 and sp through arithmetic, so no scratch register is clobbered
+    add sp, sp, x0
+    sub x0, sp, x0
+    // Boot stacks are below the kernel image, and have no guard page
+    tst x0, #0x0000fffffff80000
+    b.eq 1f
+    tst x0, #0xe000
+    sub x0, sp, x0
+    sub sp, sp, x0
+    b.eq kernel_stack_overflow
+    b 2f
+1:  sub x0, sp, x0
+    sub sp, sp, x0
+2:  handler \\kind
+.endm
+
+el1_sync:
+    el1_handler 0
+
+el1_irq:
+    el1_handler 1
+
+el1_fiq:
+    el1_handler 2
+
+el1_serror:
+    el1_handler 3
+
+kernel_stack_overflow:
+    mov x1, sp
+    // sp = EMERGENCY_STACKS + (core_id + 1) * EMERGENCY_STACK_SIZE
+    mrs x0, mpidr_el1
+    and x0, x0, #3
+    add x0, x0, #1
+    lsl x0, x0, #14
+    adrp x2, EMERGENCY_STACKS
+    add x2, x2, :lo12:EMERGENCY_STACKS
+    add sp, x2, x0
+    mov x0, x1
+    bl handle_kernel_stack_overflow
     except_hang 0
 
     .balign 4096
 exception_handlers:
     // Same exeception level, EL0
-    .align 9; b except
-    .align 7; b irq
-    .align 7; b serror
-    .align 7; b serror
+    .align 9; b except_sync
+    .align 7; b except_irq
+    .align 7; b except_fiq
+    .align 7; b except_serror
     // Same exeception level, ELx
-    .align 9; b except
-    .align 7; b irq
-    .align 7; b serror
-    .align 7; b serror
+    .align 9; b el1_sync
+    .align 7; b el1_irq
+    .align 7; b el1_fiq
+    .align 7; b el1_serror
     // Transit to upper exeception level, AArch64
-    .align 9; b except
-    .align 7; b irq
-    .align 7; b serror
-    .align 7; b serror
+    .align 9; b except_sync
+    .align 7; b except_irq
+    .align 7; b except_fiq
+    .align 7; b except_serror
     // Transit to upper exeception level, AArch32: Unreachable
-    .align 9; b except
-    .align 7; b irq
-    .align 7; b serror
-    .align 7; b serror
+    .align 9; b except_sync
+    .align 7; b except_irq
+    .align 7; b except_fiq
+    .align 7; b except_serror
 "}
\ No newline at end of file
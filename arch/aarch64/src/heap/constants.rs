@@ -3,6 +3,15 @@ use proton::memory::*;
 pub const KERNEL_CORE0_STACK_START: usize = 0xffff0000_0007c000;
 pub const KERNEL_CORE0_STACK_END:   usize = 0xffff0000_00080000;
 
+/// Boot stacks are placed right below the kernel image, one for each core.
+/// The EL1 exception entry relies on this to skip the stack overflow check on them.
+pub const KERNEL_BOOT_STACK_SIZE: usize = KERNEL_CORE0_STACK_END - KERNEL_CORE0_STACK_START;
+
+/// (Physical) top of the boot stack of a core
+pub const fn boot_stack_top(core: usize) -> usize {
+    (KERNEL_CORE0_STACK_END & 0x0000ffff_ffffffff) - core * KERNEL_BOOT_STACK_SIZE
+}
+
 /// Kernel process stack
 pub const KERNEL_STACK_PAGES: usize = 8; // Too many???
 pub const KERNEL_STACK_SIZE: usize = KERNEL_STACK_PAGES * Size4K::SIZE;
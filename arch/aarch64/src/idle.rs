@@ -1,4 +1,7 @@
 use proton_kernel::kernel_process::KernelTask;
+use proton_kernel::AbstractKernel;
+use crate::Kernel;
+use proton::IPC;
 
 
 
@@ -7,7 +10,11 @@ pub struct Idle;
 impl KernelTask for Idle {
     fn run(&mut self) -> ! {
         loop {
+            // Nothing else to run on this core, stop the periodic tick
+            Kernel::global().timer.enter_tickless_idle();
             unsafe { llvm_asm!("wfe"); }
+            // Woken up by an interrupt or an event, other tasks may be ready
+            IPC::yield_now();
         }
     }
 }
\ No newline at end of file
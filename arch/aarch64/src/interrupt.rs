@@ -1,7 +1,13 @@
+#[cfg(feature="device-raspi4")]
 use super::gic::*;
+#[cfg(feature="device-raspi3-qemu")]
+use super::bcm2835::*;
+#[cfg(feature="device-raspi3-qemu")]
+use crate::peripherals::MemoryMappedRegisters;
 use cortex_a::barrier;
 use super::exception::*;
 use proton_kernel::arch::*;
+#[cfg(feature="device-raspi4")]
 use core::intrinsics::volatile_store;
 
 pub struct InterruptController;
@@ -25,14 +31,10 @@ pub fn handle_interrupt(kind: InterruptId, exception_frame: &mut ExceptionFrame)
 }
 
 impl AbstractInterruptController for InterruptController {
+    #[cfg(feature="device-raspi4")]
     fn init() {
-        if cfg!(feature="device-raspi3-qemu") {
-            return
-        }
         #[allow(non_snake_case)]
         let GICD = GICD::get();
-        #[allow(non_snake_case)]
-        let GICC = GICC::get();
         unsafe { barrier::dsb(barrier::SY) };
         unsafe {
             // Disable all interrupts
@@ -42,7 +44,7 @@ impl AbstractInterruptController for InterruptController {
                 volatile_store(&mut GICD.ICPENDR[n], !0);
                 volatile_store(&mut GICD.ICACTIVER[n], !0);
             }
-            // Connect interrupts to core#0
+            // Connect shared peripheral interrupts to core#0
             for n in 0..(IRQ_LINES / 4) {
                 volatile_store(&mut GICD.IPRIORITYR[n], GICD::IPRIORITYRAULT | GICD::IPRIORITYRAULT << 8 | GICD::IPRIORITYRAULT << 16 | GICD::IPRIORITYRAULT << 24);
                 volatile_store(&mut GICD.ITARGETSR[n], GICD::ITARGETSR_CORE0 | GICD::ITARGETSR_CORE0 << 8 | GICD::ITARGETSR_CORE0 << 16 | GICD::ITARGETSR_CORE0 << 24);
@@ -53,11 +55,37 @@ impl AbstractInterruptController for InterruptController {
             }
             // Enable GIC
             volatile_store(&mut GICD.CTLR, GICD::CTLR_ENABLE);
+            barrier::dmb(barrier::SY);
+        }
+        Self::init_core();
+    }
+
+    #[cfg(feature="device-raspi3-qemu")]
+    fn init() {
+        unsafe { barrier::dsb(barrier::SY) };
+        // Disable all peripheral interrupts
+        ArmInterruptRegisters::get().disable_all();
+        // Peripheral interrupts are handled by core#0
+        LocalInterruptRegisters::get().gpu_interrupt_routing.set(LocalInterruptRegisters::GPU_ROUTING_CORE0);
+        unsafe { barrier::dmb(barrier::SY) };
+        Self::init_core();
+    }
+
+    #[cfg(feature="device-raspi4")]
+    fn init_core() {
+        // The CPU interface is banked for each core
+        #[allow(non_snake_case)]
+        let GICC = GICC::get();
+        unsafe {
             volatile_store(&mut GICC.PMR, GICC::PMR_PRIORITY);
             volatile_store(&mut GICC.CTLR, GICC::CTLR_ENABLE);
             barrier::dmb(barrier::SY);
         }
     }
+
+    /// Per-core interrupts are enabled by their users, e.g. the timer
+    #[cfg(feature="device-raspi3-qemu")]
+    fn init_core() {}
     
     fn is_enabled() -> bool {
         unsafe {
@@ -80,4 +108,52 @@ impl AbstractInterruptController for InterruptController {
             INTERRUPT_HANDLERS[id as usize] = handler;
         }
     }
+
+    #[cfg(feature="device-raspi4")]
+    fn enable_irq(irq: usize) -> Result<(), ()> {
+        // Only shared peripheral interrupts. SGIs and PPIs (e.g. the timer) are used by the kernel.
+        if irq < 32 || irq >= IRQ_LINES {
+            return Err(())
+        }
+        #[allow(non_snake_case)]
+        let GICD = GICD::get();
+        unsafe {
+            volatile_store(&mut GICD.ISENABLER[irq / 32], 1 << (irq % 32));
+            barrier::dmb(barrier::SY);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature="device-raspi4")]
+    fn disable_irq(irq: usize) {
+        if irq < 32 || irq >= IRQ_LINES {
+            return
+        }
+        #[allow(non_snake_case)]
+        let GICD = GICD::get();
+        unsafe {
+            volatile_store(&mut GICD.ICENABLER[irq / 32], 1 << (irq % 32));
+            barrier::dmb(barrier::SY);
+        }
+    }
+
+    /// Per-core interrupts (e.g. the timer) are not in the ARM interrupt controller
+    #[cfg(feature="device-raspi3-qemu")]
+    fn enable_irq(irq: usize) -> Result<(), ()> {
+        if irq >= IRQ_LINES {
+            return Err(())
+        }
+        ArmInterruptRegisters::get().enable_irq(irq);
+        unsafe { barrier::dmb(barrier::SY) };
+        Ok(())
+    }
+
+    #[cfg(feature="device-raspi3-qemu")]
+    fn disable_irq(irq: usize) {
+        if irq >= IRQ_LINES {
+            return
+        }
+        ArmInterruptRegisters::get().disable_irq(irq);
+        unsafe { barrier::dmb(barrier::SY) };
+    }
 }
@@ -31,6 +31,7 @@ extern crate proton_kernel;
 
 mod start;
 mod gic;
+mod bcm2835;
 mod interrupt;
 mod exception;
 mod timer;
@@ -44,7 +45,10 @@ mod bootimage;
 mod peripherals;
 
 use proton_kernel::AbstractKernel;
+#[cfg(feature="scheduler-round-robin")]
 use proton_kernel::scheduler::round_robin::RoundRobinScheduler;
+#[cfg(not(feature="scheduler-round-robin"))]
+use proton_kernel::scheduler::priority::PriorityScheduler;
 use arch::AArch64;
 
 
@@ -64,7 +68,10 @@ pub struct Kernel {
 
 impl AbstractKernel for Kernel {
     type Arch = AArch64;
+    #[cfg(feature="scheduler-round-robin")]
     type Scheduler = RoundRobinScheduler<Self>;
+    #[cfg(not(feature="scheduler-round-robin"))]
+    type Scheduler = PriorityScheduler<Self>;
 
     fn global() -> &'static Self::Global {
         &KERNEL.global
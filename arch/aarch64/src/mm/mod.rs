@@ -1,18 +1,43 @@
 pub mod page_table;
 pub mod paging;
+pub mod refcount;
 use page_table::PageFlags as ArchPageFlags;
 use page_table::{PageTable, L4};
 use proton::memory::*;
 use proton_kernel::arch::*;
 use proton_kernel::task::*;
 use crate::Kernel;
+use proton_kernel::AbstractKernel;
 use crate::arch::*;
 use proton::utils::frame_allocator::SynchronizedFrameAllocator;
-use proton::utils::frame_allocator::bump_allocator::BumpFrameAllocator;
+use proton::utils::frame_allocator::bitmap_allocator::BitMapFrameAllocator;
 
-pub static FRAME_ALLOCATOR: SynchronizedFrameAllocator<BumpFrameAllocator> = SynchronizedFrameAllocator::new(
-    BumpFrameAllocator::new((Address::new(0x2000_0000), Address::new(0x3000_0000)))
-);
+/// Physical memory available for page tables and user pages
+pub const FRAME_POOL: (Address<P>, Address<P>) = (Address::new(0x2000_0000), Address::new(0x3000_0000));
+
+pub static FRAME_ALLOCATOR: KernelFrameAllocator = KernelFrameAllocator(SynchronizedFrameAllocator::new(
+    BitMapFrameAllocator::new(FRAME_POOL)
+));
+
+/// The frame allocator shared by all cores.
+///
+/// Interrupts are disabled while holding the lock, so that the holder is never preempted
+/// (and spinned on by another task).
+pub struct KernelFrameAllocator(SynchronizedFrameAllocator<BitMapFrameAllocator>);
+
+impl KernelFrameAllocator {
+    pub fn identity_alloc<S: PageSize>(&self, frame: Frame<S>) {
+        <AArch64 as AbstractArch>::Interrupt::uninterruptable(|| self.0.identity_alloc(frame))
+    }
+
+    pub fn alloc<S: PageSize>(&self) -> Frame<S> {
+        <AArch64 as AbstractArch>::Interrupt::uninterruptable(|| self.0.alloc())
+    }
+
+    pub fn free<S: PageSize>(&self, frame: Frame<S>) {
+        <AArch64 as AbstractArch>::Interrupt::uninterruptable(|| self.0.free(frame))
+    }
+}
 
 pub struct MemoryManager;
 
@@ -26,24 +51,57 @@ impl AbstractMemoryManager for MemoryManager {
     fn dealloc_frame<S: PageSize>(frame: Frame<S>) {
         FRAME_ALLOCATOR.free(frame)
     }
+    // Page table updates are serialized by the kernel lock
+
     fn map<S: PageSize>(page: Page<S>, frame: Frame<S>, flags: PageFlags) {
-        let p4 = PageTable::<L4>::get(page.start().as_usize() & 0xffff_0000_0000_0000 != 0);
-        p4.map(page, frame, to_arch_flags::<S>(flags));
+        Kernel::critical_section(|| {
+            let p4 = PageTable::<L4>::get(page.start().as_usize() & 0xffff_0000_0000_0000 != 0);
+            p4.map(page, frame, to_arch_flags::<S>(flags));
+        })
     }
     fn translate(address: Address<V>) -> Option<(Address<P>, PageFlags)> {
         let p4 = PageTable::<L4>::get(address.as_usize() & 0xffff_0000_0000_0000 != 0);
         p4.translate(address).map(|(a, f)| (a, to_flags(f)))
     }
     fn update_flags<S: PageSize>(page: Page<S>, flags: PageFlags) {
-        let p4 = PageTable::<L4>::get(page.start().as_usize() & 0xffff_0000_0000_0000 != 0);
-        p4.update_flags(page, to_arch_flags::<S>(flags));
+        Kernel::critical_section(|| {
+            let p4 = PageTable::<L4>::get(page.start().as_usize() & 0xffff_0000_0000_0000 != 0);
+            p4.update_flags(page, to_arch_flags::<S>(flags));
+        })
     }
     fn unmap<S: PageSize>(page: Page<S>) {
-        let p4 = PageTable::<L4>::get(page.start().as_usize() & 0xffff_0000_0000_0000 != 0);
-        p4.unmap(page);
+        Kernel::critical_section(|| {
+            let p4 = PageTable::<L4>::get(page.start().as_usize() & 0xffff_0000_0000_0000 != 0);
+            let mapping = p4.translate(page.start());
+            p4.unmap(page);
+            paging::invalidate_tlb();
+            // Release the user frame
+            if let Some((address, flags)) = mapping {
+                if flags.contains(ArchPageFlags::USER) && flags.contains(ArchPageFlags::NORMAL_MEMORY) {
+                    refcount::release::<S>(Frame::new(address));
+                }
+            }
+        })
+    }
+    fn zero_frame<S: PageSize>(frame: Frame<S>) {
+        // The temporary page must not be used by another task on this core
+        Kernel::critical_section(|| {
+            let flags = if S::SIZE == Size4K::SIZE { ArchPageFlags::_KERNEL_DATA_FLAGS_4K } else { ArchPageFlags::_KERNEL_DATA_FLAGS_2M };
+            let page = page_table::map_kernel_temporarily(frame, flags, None);
+            unsafe { page.zero(); }
+        })
+    }
+    fn with_user_address_space<R, F: FnOnce() -> R>(task: TaskId, f: F) -> R {
+        Kernel::critical_section(|| {
+            let p4 = Task::<Kernel>::by_id(task).expect("task not found").context.p4;
+            PageTable::<L4>::with_temporary_low_table(p4, |_| f())
+        })
+    }
+    fn handle_user_fault(task: TaskId, address: Address<V>) -> Result<(), ()> {
+        Kernel::critical_section(|| resolve_user_fault(task, address))
     }
     fn map_user<S: PageSize>(task: TaskId, page: Page<S>, frame: Frame<S>, flags: PageFlags) {
-        <AArch64 as AbstractArch>::Interrupt::uninterruptable(|| {
+        Kernel::critical_section(|| {
             let ctx = &Task::<Kernel>::by_id(task).unwrap().context;
             // Set pagetable
             unsafe {
@@ -88,7 +146,11 @@ fn to_arch_flags<S: PageSize>(flags: PageFlags) -> ArchPageFlags {
         aflags |= ArchPageFlags::SMALL_PAGE;
     }
     aflags |= ArchPageFlags::OUTER_SHARE;
-    aflags |= ArchPageFlags::NORMAL_MEMORY;
+    if flags.contains(PageFlags::NO_CACHE) {
+        aflags |= ArchPageFlags::DEVICE_MEMORY;
+    } else {
+        aflags |= ArchPageFlags::NORMAL_MEMORY;
+    }
     aflags
 }
 
@@ -115,19 +177,45 @@ fn to_flags(aflags: ArchPageFlags) -> PageFlags {
     if aflags.contains(ArchPageFlags::NO_EXEC) {
         flags |= PageFlags::NO_EXEC;
     }
+    if !aflags.contains(ArchPageFlags::NORMAL_MEMORY) {
+        flags |= PageFlags::NO_CACHE;
+    }
     flags
 }
 
-pub fn handle_user_pagefault(address: Address) {
+/// Resolve a page fault in the current task's address space.
+///
+/// Returns `Err` if the address is outside the task's memory regions,
+/// or the access is not permitted by the region.
+pub fn handle_user_pagefault(address: Address) -> Result<(), ()> {
+    let task = Task::<Kernel>::current().ok_or(())?;
+    resolve_user_fault(task.id(), address)
+}
+
+/// Resolve a fault at `address` of `task`, whose page table must be the current user page table
+fn resolve_user_fault(task: TaskId, address: Address) -> Result<(), ()> {
+    let task = Task::<Kernel>::by_id(task).ok_or(())?;
+    let region = task.memory_regions.lock().find(address).cloned().ok_or(())?;
     let p4 = PageTable::<L4>::get(false);
-    if let Some((_, flags)) = p4.translate(address) {
-        if flags.contains(ArchPageFlags::COPY_ON_WRITE) {
-            p4.fix_copy_on_write(address, !flags.contains(ArchPageFlags::SMALL_PAGE));
-            return
+    match p4.translate(address) {
+        Some((_, flags)) => {
+            if flags.contains(ArchPageFlags::COPY_ON_WRITE) && !region.flags.contains(PageFlags::NO_WRITE) {
+                p4.fix_copy_on_write(address, !flags.contains(ArchPageFlags::SMALL_PAGE));
+                return Ok(())
+            }
+        }
+        None if region.is_demand_paged() => {
+            // First access, allocate a zeroed frame
+            let frame = FRAME_ALLOCATOR.alloc::<Size4K>();
+            MemoryManager::zero_frame(frame);
+            p4.map(Page::<Size4K>::of(address), frame, to_arch_flags::<Size4K>(region.flags));
+            paging::invalidate_tlb();
+            return Ok(())
         }
+        None => {}
     }
-    debug!(crate::Kernel: "Page Fault at {:?}", address);
-    unimplemented!()
+    debug!(crate::Kernel: "Page Fault at {:?} in {:?}", address, region);
+    Err(())
 }
 
 pub fn is_copy_on_write_address(address: Address) -> bool {
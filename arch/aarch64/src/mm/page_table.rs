@@ -2,6 +2,8 @@ use cortex_a::regs::*;
 use core::marker::PhantomData;
 use super::FRAME_ALLOCATOR;
 use proton::memory::*;
+use proton_kernel::arch::*;
+use crate::arch::AArch64;
 
 
 bitflags! {
@@ -314,7 +316,7 @@ impl PageTable<L4> {
         entry.clear();
     }
 
-    pub fn with_temporary_low_table<R>(new_p4_frame: Frame, f: impl Fn(&'static mut PageTable<L4>) -> R) -> R {
+    pub fn with_temporary_low_table<R>(new_p4_frame: Frame, f: impl FnOnce(&'static mut PageTable<L4>) -> R) -> R {
         let old_p4_frame = Frame::<Size4K>::new((TTBR0_EL1.get() as usize).into());
         TTBR0_EL1.set(new_p4_frame.start().as_usize() as u64);
         super::paging::invalidate_tlb();
@@ -363,16 +365,19 @@ impl <L: TableLevel> PageTable<L> {
                     let new_table = unsafe { page.start().as_ref_mut::<Self>() };
 
                     let old_flags = self.entries[i].flags();
-                    if old_flags.contains(PageFlags::NO_WRITE) {
-                        // FIXME: What if child process updates this flag as writeable?
-                        continue; // Skip since it is readonly already
-                    }
-                    let flags = old_flags | PageFlags::COPY_ON_WRITE | PageFlags::NO_WRITE;
+                    let flags = if old_flags.contains(PageFlags::NO_WRITE) || !old_flags.contains(PageFlags::NORMAL_MEMORY) {
+                        // Readonly or device pages are simply shared
+                        old_flags
+                    } else {
+                        old_flags | PageFlags::COPY_ON_WRITE | PageFlags::NO_WRITE
+                    };
                     let addr = self.entries[i].address();
                     self.entries[i].update_flags(flags);
                     if flags.contains(PageFlags::SMALL_PAGE) {
+                        super::refcount::retain::<Size4K>(Frame::new(addr));
                         new_table.entries[i].set::<Size4K>(Frame::new(addr), flags);
                     } else {
+                        super::refcount::retain::<Size2M>(Frame::new(addr));
                         new_table.entries[i].set::<Size2M>(Frame::new(addr), flags);
                     }
                 }
@@ -389,6 +394,37 @@ impl <L: TableLevel> PageTable<L> {
         new_table_frame
     }
 
+    /// Release a (user) page table hierarchy
+    ///
+    /// This will free all child page tables, and release all normal-memory pages.
+    /// Shared pages are only freed by their last owner.
+    /// Device memory pages are not owned by the task, so they are only unmapped.
+    /// The frame of this table itself is not freed.
+    pub fn release(&mut self) {
+        if L::ID == 0 { unreachable!() }
+
+        let limit = if L::ID == 4 { 511 } else { 512 };
+        for i in 0..limit {
+            if !self.entries[i].present() {
+                continue;
+            }
+            let flags = self.entries[i].flags();
+            let address = self.entries[i].address();
+            if L::ID != 1 && flags.contains(PageFlags::SMALL_PAGE) {
+                // This entry is a page table
+                self.next_table(i).unwrap().release();
+                FRAME_ALLOCATOR.free::<Size4K>(Frame::new(address));
+            } else if flags.contains(PageFlags::NORMAL_MEMORY) {
+                // This entry points to a page
+                if L::ID == 1 {
+                    super::refcount::release::<Size4K>(Frame::new(address));
+                } else {
+                    super::refcount::release::<Size2M>(Frame::new(address));
+                }
+            }
+            self.entries[i].clear();
+        }
+    }
 }
 
 impl PageTable<L4> {
@@ -401,6 +437,14 @@ impl PageTable<L4> {
             let p1 = p2.next_table(PageTable::<L2>::get_index(a)).unwrap();
             let p1_index = PageTable::<L1>::get_index(a);
             debug_assert!(p1.entries[p1_index].flags().contains(PageFlags::COPY_ON_WRITE));
+            let new_flags = p1.entries[p1_index].flags() - PageFlags::COPY_ON_WRITE - PageFlags::NO_WRITE;
+            let old_frame = Frame::<Size4K>::new(p1.entries[p1_index].address());
+            if !super::refcount::is_shared(old_frame) {
+                // We are the only owner, simply make it writable again
+                p1.entries[p1_index].update_flags(new_flags);
+                super::paging::invalidate_tlb();
+                return;
+            }
             let old_page = Page::<Size4K>::of(a);
             let new_frame = FRAME_ALLOCATOR.alloc::<Size4K>();
             {
@@ -415,8 +459,10 @@ impl PageTable<L4> {
                     offset += Address::<V>::SIZE;
                 }
             }
-            let new_flags = p1.entries[p1_index].flags() - PageFlags::COPY_ON_WRITE - PageFlags::NO_WRITE;
             p1.entries[p1_index].set(new_frame, new_flags);
+            super::paging::invalidate_tlb();
+            // Drop our reference to the shared frame
+            super::refcount::release(old_frame);
         }
     }
 }
@@ -441,9 +487,13 @@ impl <S: PageSize> Drop for TemporaryKernelPage<S> {
     }
 }
 
+/// Map a frame to a per-core temporary kernel page.
+/// The caller must not be preempted until the page is dropped.
 pub fn map_kernel_temporarily<S: PageSize>(frame: Frame<S>, flags: PageFlags, p: Option<usize>) -> TemporaryKernelPage<S> {
     const MAGIC_PAGE: usize = 0xffff_1234_5600_0000;
-    let page = Page::new(p.unwrap_or(MAGIC_PAGE).into());
+    debug_assert!(!<AArch64 as AbstractArch>::Interrupt::is_enabled());
+    let magic_page = MAGIC_PAGE + <AArch64 as AbstractArch>::core_id() * Size2M::SIZE;
+    let page = Page::new(p.unwrap_or(magic_page).into());
     PageTable::<L4>::get(true).map(page, frame, flags);
     // map_kernel(page, frame, flags);
     super::paging::invalidate_tlb();
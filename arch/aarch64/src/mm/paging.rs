@@ -63,11 +63,60 @@ unsafe fn setup_initial_ttbr() {
     }
     // Set page table register 0
     KERNEL_P4.entries[511].set::<Size4K>(Frame::new(Address::from(&KERNEL_P4 as *const _)), PageFlags::_PAGE_TABLE_FLAGS);
+    set_kernel_ttbr();
+}
+
+unsafe fn set_kernel_ttbr() {
     let p4 = &KERNEL_P4 as *const PageTable<L4>;
     TTBR0_EL1.set(p4 as u64 & 0x0000ffff_ffffffff);
     TTBR1_EL1.set(p4 as u64 & 0x0000ffff_ffffffff);
 }
 
+unsafe fn setup_mair() {
+    MAIR_EL1.write(
+        // Attribute 1 - Cacheable normal DRAM.
+        MAIR_EL1::Attr1_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc +
+        MAIR_EL1::Attr1_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc +
+        // Attribute 0 - Device.
+        MAIR_EL1::Attr0_Device::nonGathering_nonReordering_EarlyWriteAck,
+    );
+}
+
+unsafe fn setup_tcr() {
+    TCR_EL1.write(
+        //   TCR_EL1::IPS.val(0b101)
+        TCR_EL1::TG0::KiB_4
+        + TCR_EL1::TG1::KiB_4
+        + TCR_EL1::SH0::Inner
+        + TCR_EL1::SH1::Inner
+        + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::EPD0::EnableTTBR0Walks
+        + TCR_EL1::EPD1::EnableTTBR1Walks
+        // + TCR_EL1::T0SZ.val(0x10)
+        // + TCR_EL1::T1SZ.val(0x10)
+    );
+    TCR_EL1.set(TCR_EL1.get() | 0b101 << 32); // Intermediate Physical Address Size (IPS) = 0b101
+    TCR_EL1.set(TCR_EL1.get() | 0x10 <<  0); // TTBR0_EL1 memory size (T0SZ) = 0x10 ==> 2^(64 - T0SZ)
+    TCR_EL1.set(TCR_EL1.get() | 0x10 << 16); // TTBR1_EL1 memory size (T1SZ) = 0x10 ==> 2^(64 - T1SZ)
+}
+
+unsafe fn enable_mmu() {
+    barrier::isb(barrier::SY);
+    SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
+    barrier::isb(barrier::SY);
+}
+
+/// Enable MMU on a secondary core, with the kernel page table created by core 0
+pub unsafe fn setup_secondary_core_pagetables() {
+    setup_mair();
+    set_kernel_ttbr();
+    setup_tcr();
+    enable_mmu();
+}
+
 pub fn clear_temp_user_pagetable() {
     // unsafe {
     //     for i in 0..511 {
@@ -104,44 +153,19 @@ pub unsafe fn setup_kernel_pagetables() {
     };
 
     boot_time_log("[boot: (mmu) setup MAIR]");
-    MAIR_EL1.write(
-        // Attribute 1 - Cacheable normal DRAM.
-        MAIR_EL1::Attr1_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc +
-        MAIR_EL1::Attr1_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc +
-        // Attribute 0 - Device.
-        MAIR_EL1::Attr0_Device::nonGathering_nonReordering_EarlyWriteAck,
-    );
+    setup_mair();
 
     boot_time_log("[boot: (mmu) setup TTBRx registers]");
     setup_initial_ttbr();
 
     boot_time_log("[boot: (mmu) setup TCR]");
     assert!(TCR_EL1.get() == 0);
-    TCR_EL1.write(
-        //   TCR_EL1::IPS.val(0b101)
-        TCR_EL1::TG0::KiB_4
-        + TCR_EL1::TG1::KiB_4
-        + TCR_EL1::SH0::Inner
-        + TCR_EL1::SH1::Inner
-        + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::EPD0::EnableTTBR0Walks
-        + TCR_EL1::EPD1::EnableTTBR1Walks
-        // + TCR_EL1::T0SZ.val(0x10)
-        // + TCR_EL1::T1SZ.val(0x10)
-    );
-    TCR_EL1.set(TCR_EL1.get() | 0b101 << 32); // Intermediate Physical Address Size (IPS) = 0b101
-    TCR_EL1.set(TCR_EL1.get() | 0x10 <<  0); // TTBR0_EL1 memory size (T0SZ) = 0x10 ==> 2^(64 - T0SZ)
-    TCR_EL1.set(TCR_EL1.get() | 0x10 << 16); // TTBR1_EL1 memory size (T1SZ) = 0x10 ==> 2^(64 - T1SZ)
+    setup_tcr();
 
 
     // Enable MMU and turn on data/instruction caching.
     boot_time_log("[boot: (mmu) enable mmu]");
-    barrier::isb(barrier::SY);
-    SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
-    barrier::isb(barrier::SY);
+    enable_mmu();
 
     // Mark kernel stack/heap and device physical memory as occupied
     boot_time_log("[boot: (mmu) alloc kernel & device frames]");
@@ -200,12 +224,7 @@ fn mark_as_used<S: PageSize>(start_frame: Frame<S>, n_frames: usize) {
     
     Frame::range(start_frame, limit_frame, |frame| {
         // boot_time_log("[boot: mark_as_used loop 1]");
-        use proton::utils::frame_allocator::FrameAllocator;
-        let mut fa = super::FRAME_ALLOCATOR.fa.lock();
-        // // boot_time_log("[boot: mark_as_used loop 2]");
-        fa.identity_alloc(frame);
-        // boot_time_log("[boot: mark_as_used loop 3]");
-        // super::FRAME_ALLOCATOR.identity_alloc(frame);
+        super::FRAME_ALLOCATOR.identity_alloc(frame);
     });
     // loop {}
 }
@@ -234,6 +253,14 @@ pub fn fork_page_table(parent_p4_frame: Frame) -> Frame {
     })
 }
 
+/// Free a user page table hierarchy, together with all the pages it maps
+pub fn release_page_table(p4_frame: Frame) {
+    PageTable::<L4>::with_temporary_low_table(p4_frame, |p4| {
+        p4.release()
+    });
+    super::FRAME_ALLOCATOR.free(p4_frame);
+}
+
 pub fn invalidate_tlb() {
     unsafe {
         llvm_asm! {"
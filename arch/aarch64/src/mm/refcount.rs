@@ -0,0 +1,55 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+use proton::memory::*;
+use super::{FRAME_ALLOCATOR, FRAME_POOL};
+
+const FRAMES: usize = (FRAME_POOL.1.as_usize() - FRAME_POOL.0.as_usize()) >> Size4K::LOG_SIZE;
+
+/// Number of *additional* owners of each frame in `FRAME_POOL`.
+///
+/// A freshly allocated frame has one owner and a count of zero,
+/// so only frames shared by `fork()` need to be tracked.
+static SHARED_COUNTS: [AtomicU16; FRAMES] = [AtomicU16::new(0); FRAMES];
+
+#[inline]
+fn counter<S: PageSize>(frame: Frame<S>) -> Option<&'static AtomicU16> {
+    let a = frame.start();
+    if a < FRAME_POOL.0 || a >= FRAME_POOL.1 {
+        // Kernel or device memory, not ref-counted
+        None
+    } else {
+        Some(&SHARED_COUNTS[(a - FRAME_POOL.0) >> Size4K::LOG_SIZE])
+    }
+}
+
+/// Add an owner to this frame
+pub fn retain<S: PageSize>(frame: Frame<S>) {
+    if let Some(count) = counter(frame) {
+        let old = count.fetch_add(1, Ordering::SeqCst);
+        assert!(old != u16::MAX, "Too many references to {:?}", frame);
+    }
+}
+
+/// Remove an owner from this frame. The last owner frees it.
+///
+/// Returns `true` if the frame is freed.
+pub fn release<S: PageSize>(frame: Frame<S>) -> bool {
+    let count = match counter(frame) {
+        Some(count) => count,
+        None => return false,
+    };
+    loop {
+        let old = count.load(Ordering::SeqCst);
+        if old == 0 {
+            FRAME_ALLOCATOR.free(frame);
+            return true;
+        }
+        if count.compare_exchange(old, old - 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            return false;
+        }
+    }
+}
+
+/// Whether this frame is mapped by more than one owner
+pub fn is_shared<S: PageSize>(frame: Frame<S>) -> bool {
+    counter(frame).map(|count| count.load(Ordering::SeqCst) != 0).unwrap_or(false)
+}
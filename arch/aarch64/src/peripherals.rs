@@ -6,6 +6,13 @@ pub const PERIPHERAL_BASE: usize = 0xFFFF0000_3F000000;
 #[cfg(feature="device-raspi4")]
 pub const PERIPHERAL_BASE: usize = 0xFFFF0000_FE000000;
 
+/// Interrupt of the EMMC controller
+#[cfg(feature="device-raspi3-qemu")]
+pub const EMMC_IRQ: usize = 62;
+/// Interrupt of the EMMC controller (GIC SPI 126)
+#[cfg(feature="device-raspi4")]
+pub const EMMC_IRQ: usize = 158;
+
 
 
 pub trait MemoryMappedRegisters: Sized {
@@ -1,40 +1,86 @@
 use super::*;
 use cortex_a::{asm, regs::*, barrier};
 use super::uart::boot_time_log;
+use crate::heap::constants::boot_stack_top;
 use proton_kernel::*;
 
 pub static mut BOOTED: bool = false;
 
+/// Set by core 0 to release the secondary cores.
+/// Secondary cores read it with MMU and caches off.
+#[no_mangle]
+pub static mut SECONDARY_CORES_RELEASED: usize = 0;
+
+/// Spin table used by the firmware to park secondary cores (core 1~3)
+const SPIN_TABLE: usize = 0xffff0000_000000d8;
+
 #[no_mangle]
 #[naked]
 pub unsafe fn _start() -> ! {
-    // Halt non-promary processors
+    // Park secondary cores until they are released by core 0,
+    // and setup the boot stack of each core
     llvm_asm! {"
             mrs     x0, mpidr_el1
             and     x0, x0, #3
             cbz     x0, 2f
         1:  wfe
-            b       1b
-        2:
+            adrp    x1, SECONDARY_CORES_RELEASED
+            add     x1, x1, :lo12:SECONDARY_CORES_RELEASED
+            ldr     x1, [x1]
+            cbz     x1, 1b
+        2:  mov     x1, #0x80000
+            lsl     x0, x0, #14
+            sub     x1, x1, x0
+            mov     sp, x1
     "};
-    // Setup core 0 stack
-    llvm_asm!("mov sp, $0"::"r"(0x80000));
-    
-    super::uart::UART0::init();
+    let core = MPIDR_EL1.get() as usize & 0b11;
+    if core == 0 {
+        super::uart::UART0::init();
+    }
     assert!(CurrentEL.get() == CurrentEL::EL::EL2.value);
-    boot_time_log("[boot...]");
+    if core == 0 {
+        boot_time_log("[boot...]");
+    }
     CNTHCTL_EL2.write(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
     CNTVOFF_EL2.set(0);
     // Switch to EL1
-    boot_time_log("[boot: switch to exception level 1...]");
+    if core == 0 {
+        boot_time_log("[boot: switch to exception level 1...]");
+    }
     SCTLR_EL1.set((3 << 28) | (3 << 22) | (1 << 20) | (1 << 11)); // Disable MMU
     HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64); // Set execution mode = AArch64
     SPSR_EL2.write(SPSR_EL2::D::Masked + SPSR_EL2::A::Masked + SPSR_EL2::I::Masked + SPSR_EL2::F::Masked + SPSR_EL2::M::EL1h);
     ELR_EL2.set(_start_el1 as *const () as u64); // EL1 PC after return from `eret`
-    SP_EL1.set(0x80000); // EL1 stack
+    SP_EL1.set(boot_stack_top(core) as u64); // EL1 stack
     asm::eret();
 }
 
+/// Release core 1~3. Called by core 0 after the kernel is initialized.
+pub fn start_secondary_cores() {
+    unsafe {
+        ::core::intrinsics::volatile_store(&mut SECONDARY_CORES_RELEASED, 1);
+        clean_dcache_line(&SECONDARY_CORES_RELEASED as *const usize as usize);
+        // Firmware may hold the secondary cores in the spin table, instead of entering `_start`
+        let entry = _start as *const () as usize & 0x0000ffff_ffffffff;
+        for core in 1..4 {
+            let slot = (SPIN_TABLE + (core - 1) * 8) as *mut usize;
+            ::core::intrinsics::volatile_store(slot, entry);
+            clean_dcache_line(slot as usize);
+        }
+        llvm_asm!("sev");
+    }
+}
+
+/// Write back a cache line to memory, so that cores with caches off can see it
+unsafe fn clean_dcache_line(address: usize) {
+    llvm_asm! {"
+        dc civac, $0
+        dsb sy
+    "
+    ::"r"(address)
+    }
+}
+
 
 extern {
     static mut __bss_start: usize;
@@ -55,15 +101,23 @@ unsafe fn zero_bss() {
 /// Starting from this function,
 /// kernel code is running in Exception Level 1
 unsafe extern fn _start_el1() -> ! {
+    let core = MPIDR_EL1.get() as usize & 0b11;
     // Enable all co-processors
-    boot_time_log("[boot: enable all co-processors]");
+    if core == 0 {
+        boot_time_log("[boot: enable all co-processors]");
+    }
     llvm_asm!("msr cpacr_el1, $0"::"r"(0xfffffff));
-    boot_time_log("[boot: zero bss]");
-    zero_bss();
-    // Setup paging
-    boot_time_log("[boot: setup kernel pagetable]");
-    super::mm::paging::setup_kernel_pagetables();
-    boot_time_log("[boot: switch to high address space]");
+    if core == 0 {
+        boot_time_log("[boot: zero bss]");
+        zero_bss();
+        // Setup paging
+        boot_time_log("[boot: setup kernel pagetable]");
+        super::mm::paging::setup_kernel_pagetables();
+        boot_time_log("[boot: switch to high address space]");
+    } else {
+        // Reuse the page table created by core 0
+        super::mm::paging::setup_secondary_core_pagetables();
+    }
     // loop {}
     SP.set(SP.get() | 0xffff0000_00000000);
     let fn_addr = _start_el1_high_address_space as usize | 0xffff0000_00000000;
@@ -76,7 +130,10 @@ unsafe extern fn _start_el1() -> ! {
 /// Including SP, PC and other registers
 /// i.e. `address & 0xffff0000_00000000 == 0xffff0000_00000000`
 unsafe extern fn _start_el1_high_address_space() -> ! {
-    BOOTED = true;
+    let core = MPIDR_EL1.get() as usize & 0b11;
+    if core == 0 {
+        BOOTED = true;
+    }
     // println!("[boot: clear temporary user page table]");
     super::mm::paging::clear_temp_user_pagetable();
     // Set EL1 interrupt vector
@@ -87,6 +144,10 @@ unsafe extern fn _start_el1_high_address_space() -> ! {
     // set_booted();
     
     TTBR0_EL1.set(0);
+
+    if core != 0 {
+        <Kernel as AbstractKernel>::start_secondary();
+    }
     
     debug!(Kernel: "[boot: kernel_end = 0x{:x}]", crate::heap::constants::kernel_end());
     debug!(Kernel: "[boot: kernel_heap_end = 0x{:x}]", crate::heap::constants::kernel_heap_end());
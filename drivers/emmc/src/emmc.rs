@@ -14,7 +14,9 @@ pub struct EMMC;
 impl EMMC {
     unsafe fn wait_for(mask: u32) {
         let emmc = &mut *EMMCData::BASE;
-        while (emmc.status & mask) != 0 && (emmc.interrupt & emmc::INT_ERROR_MASK) == 0 {}
+        while (emmc.status & mask) != 0 && (emmc.interrupt & emmc::INT_ERROR_MASK) == 0 {
+            sleep(1);
+        }
         // log!("EMMC_INTERRUPT {:?} {:?}", *EMMC_INTERRUPT, *EMMC_INTERRUPT & INT_ERROR_MASK);
         if emmc.interrupt & emmc::INT_ERROR_MASK != 0 {
             panic!("EMMC INT ERROR");
@@ -368,7 +370,6 @@ fn wait_cycles(n: usize) {
     for _ in 0..n {}
 }
 
-fn sleep(_ms: usize) {
-    for _ in 0..10000 {}
-    // ::proton::KernelCall::sleep().unwrap();
+fn sleep(ms: usize) {
+    KernelCall::sleep(ms).unwrap();
 }
\ No newline at end of file
@@ -10,7 +10,7 @@ mod emmc;
 mod constants;
 mod fat;
 
-use proton::Message;
+use proton::{Message, KernelCall, Priority};
 use proton::driver::Driver;
 
 pub struct EMMCDriver;
@@ -19,6 +19,8 @@ driver_entry!(EMMCDriver);
 
 impl Driver for EMMCDriver {
     fn new() -> Self {
+        // Preempt compute-bound user programs
+        KernelCall::set_priority(None, Priority::HIGH).unwrap();
         emmc::EMMC::init().unwrap();
         fat::FAT::init().unwrap();
         fat::FAT::ls_root();
@@ -7,11 +7,13 @@ extern crate proton;
 
 #[macro_use]
 mod log;
+mod tests;
 
 #[no_mangle]
 pub extern fn _start(_argc: isize, _argv: *const *const u8) -> isize {
     // unsafe { llvm_asm!("1:  b 1b") }
     log!("Init process start (user mode)");
+    tests::run();
 
     // let msg = Message {
     //     sender: 0,
@@ -34,7 +36,7 @@ pub extern fn _start(_argc: isize, _argv: *const *const u8) -> isize {
 
     // let id = KernelCall::fork().unwrap();
     // log!("Fork return -> {:?}", id);
-    loop {}
+    proton::KernelCall::exit(0);
     // unreachable!();
     // let id = syscall!(SysCall::Fork);
     // log!("Hello from init process! <{}>", id);
@@ -0,0 +1,25 @@
+use proton::task::{Message, TaskId};
+use proton::ipc::IpcError;
+use proton::KernelCall;
+
+/// IPC tests, run by the init process at boot
+pub fn run() {
+    server_exits_during_send_receive();
+}
+
+/// A client waiting for the reply of `send_receive` is woken up when the server exits
+fn server_exits_during_send_receive() {
+    match KernelCall::fork().unwrap() {
+        Some(server) => {
+            let request = Message::new(TaskId::NULL, server, 0);
+            let result = request.send_receive();
+            assert!(result.err() == Some(IpcError::DeadDestination), "send_receive to an exited server: {:?}", result);
+            log!("[test] server_exits_during_send_receive ... ok");
+        }
+        None => {
+            // Take the request, and exit without replying
+            let _ = Message::receive(None).unwrap();
+            KernelCall::exit(0);
+        }
+    }
+}
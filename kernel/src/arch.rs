@@ -3,6 +3,7 @@ use proton::memory::*;
 use alloc::boxed::Box;
 use crate::kernel_process::KernelTask;
 use proton::task::TaskId;
+use crate::privilege::Privileges;
 
 
 #[repr(usize)]
@@ -18,6 +19,8 @@ pub type InterruptHandler = Box<dyn Fn(usize, usize, usize, usize, usize, usize)
 
 pub trait AbstractInterruptController: Sized + 'static {
     fn init();
+    /// Initialize the per-core interrupt interface on a secondary core
+    fn init_core();
     
     fn is_enabled() -> bool;
     fn enable();
@@ -25,6 +28,11 @@ pub trait AbstractInterruptController: Sized + 'static {
 
     fn set_handler(id: InterruptId, handler: Option<InterruptHandler>);
 
+    /// Unmask the device interrupt `irq`. Fails if `irq` can't be used by drivers.
+    fn enable_irq(irq: usize) -> Result<(), ()>;
+    /// Mask the device interrupt `irq`
+    fn disable_irq(irq: usize);
+
     #[inline]
     fn uninterruptable<R, F: FnOnce() -> R>(f: F) -> R {
         let enabled = Self::is_enabled();
@@ -62,11 +70,26 @@ pub trait AbstractMemoryManager: Sized {
     fn translate(address: Address<V>) -> Option<(Address<P>, PageFlags)>;
     fn update_flags<S: PageSize>(page: Page<S>, flags: PageFlags);
     fn unmap<S: PageSize>(page: Page<S>);
+    fn zero_frame<S: PageSize>(frame: Frame<S>);
+    /// Resolve a fault at `address` of `task` (e.g. copy-on-write or demand paging),
+    /// in the current user address space. Used when the kernel accesses user memory.
+    fn handle_user_fault(task: TaskId, address: Address<V>) -> Result<(), ()>;
+    /// Run `f` with the user address space of the given task
+    fn with_user_address_space<R, F: FnOnce() -> R>(task: TaskId, f: F) -> R;
     // fn map_temporarily<S: PageSize>(page: Page<S>, frame: Frame<S>, flags: PageFlags) -> TemporaryPage<S>;
 }
 
 pub trait AbstractTimer: Sized {
     fn init();
+    /// Start the timer of a secondary core
+    fn init_core();
+    /// Program the timer interrupt of this core `ticks` ticks after the last one, instead of the next tick.
+    /// Used to stop the periodic tick on idle cores.
+    fn set_next_tick(ticks: usize);
+    /// Monotonic time since boot, in nanoseconds
+    fn now() -> u64;
+    /// Frequency of the underlying counter, in Hz
+    fn counter_frequency() -> u64;
     fn wait(ms: usize);
 }
 
@@ -74,9 +97,14 @@ pub trait AbstractContext: Sized + 'static {
     fn empty() -> Self;
     fn new(entry: *const extern fn(a: *mut ()) -> !, ctx: *mut ()) -> Self;
     fn new2();
-    // fn fork(&self) -> Self;
+    fn fork(&self) -> Self;
+    /// Discard the current exception frame, and restart the task in kernel mode at `entry`
+    fn restart_in_kernel(&mut self, entry: *const extern fn(a: *mut ()) -> !, ctx: *mut ());
     fn set_response_message(&mut self, m: crate::task::Message);
     fn set_response_status(&mut self, s: isize);
+    /// Whether the exception being handled was taken from user mode.
+    /// Kernel tasks pass kernel pointers to IPC calls.
+    fn is_user_mode(&self) -> bool;
     unsafe extern fn return_to_user(&mut self) -> !;
     unsafe fn enter_usermode(entry: extern fn(_argc: isize, _argv: *const *const u8), sp: Address) -> !;
 }
@@ -92,6 +120,8 @@ pub trait AbstractKernelHeap: Sized + 'static {
 
 pub trait AbstractBootImage: Sized + 'static {
     fn get(file: &str) -> Option<&'static [u8]>;
+    /// Privileges of the task loaded from `file`
+    fn privileges(file: &str) -> Privileges;
 }
 
 pub trait AbstractArch: Sized + 'static {
@@ -104,4 +134,10 @@ pub trait AbstractArch: Sized + 'static {
     type BootImage: AbstractBootImage;
     
     fn create_idle_task() -> Box<dyn KernelTask>;
+    /// Id of the current core, in `0..MAX_CORES`
+    fn core_id() -> usize;
+    /// Release all the secondary cores. They will enter `AbstractKernel::start_secondary`.
+    fn start_secondary_cores();
+    /// Wake up the cores waiting in the idle task
+    fn wake_idle_cores();
 }
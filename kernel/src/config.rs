@@ -0,0 +1,70 @@
+use core::str;
+use crate::arch::*;
+use crate::*;
+
+/// Kernel options, parsed at boot from the `cmdline` file of the boot image.
+///
+/// The command line is a list of space-separated `key=value` options, e.g. `tick=100 timeslice=10 tickless=on`.
+/// Unknown options and invalid values are ignored.
+#[derive(Debug, Clone)]
+pub struct KernelConfig {
+    /// Number of timer ticks per second (`tick`)
+    pub tick_frequency: usize,
+    /// Length of a time slice, in ticks (`timeslice`)
+    pub time_slice: usize,
+    /// Stop the periodic tick while a core is idle (`tickless`)
+    pub tickless_idle: bool,
+}
+
+impl KernelConfig {
+    pub const fn new() -> Self {
+        Self {
+            tick_frequency: 100,
+            time_slice: 10,
+            tickless_idle: true,
+        }
+    }
+
+    pub fn parse(cmdline: &str) -> Self {
+        let mut config = Self::new();
+        for option in cmdline.split_whitespace() {
+            let mut kv = option.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("tick"), Some(v)) => {
+                    if let Some(v) = Self::parse_positive(v) {
+                        config.tick_frequency = v;
+                    }
+                }
+                (Some("timeslice"), Some(v)) => {
+                    if let Some(v) = Self::parse_positive(v) {
+                        config.time_slice = v;
+                    }
+                }
+                (Some("tickless"), Some("on")) => config.tickless_idle = true,
+                (Some("tickless"), Some("off")) => config.tickless_idle = false,
+                _ => {}
+            }
+        }
+        config
+    }
+
+    fn parse_positive(v: &str) -> Option<usize> {
+        v.parse().ok().filter(|v| *v > 0)
+    }
+
+    /// Load the config from the boot image, or use the default config
+    pub fn load<K: AbstractKernel>() -> Self {
+        let mut config = <K::Arch as AbstractArch>::BootImage::get("cmdline")
+            .and_then(|cmdline| str::from_utf8(cmdline).ok())
+            .map(Self::parse)
+            .unwrap_or_else(Self::new);
+        // A tick can't be shorter than one cycle of the timer counter
+        let counter_frequency = <K::Arch as AbstractArch>::Timer::counter_frequency() as usize;
+        config.tick_frequency = usize::min(config.tick_frequency, usize::max(counter_frequency, 1));
+        config
+    }
+}
+
+impl Default for KernelConfig {
+    fn default() -> Self { Self::new() }
+}
@@ -9,8 +9,12 @@ use core::marker::PhantomData;
 #[inline(never)]
 pub fn _print<K: AbstractKernel>(args: fmt::Arguments) {
     <K::Arch as AbstractArch>::Interrupt::uninterruptable(|| {
-        let _guard = LOGGER_LOCK.lock();
-        let mut write = Logger::<K::Arch>(PhantomData);
+        let mut at_line_start = LOGGER_LOCK.lock();
+        let mut write = TimestampedLogger {
+            logger: Logger::<K::Arch>(PhantomData),
+            now: <K::Arch as AbstractArch>::Timer::now(),
+            at_line_start: &mut at_line_start,
+        };
         write.write_fmt(args).unwrap();
     });
 }
@@ -22,7 +26,8 @@ macro_rules! debug {
     }};
 }
 
-static LOGGER_LOCK: Mutex<()> = Mutex::new(());
+/// Whether the next character starts a new line of output
+static LOGGER_LOCK: Mutex<bool> = Mutex::new(true);
 
 struct Logger<Arch: AbstractArch>(PhantomData<Arch>);
 
@@ -43,3 +48,26 @@ impl <Arch: AbstractArch> Write for Logger<Arch> {
         Ok(())
     }
 }
+
+/// Prefixes each line of output with a timestamp. A line may be printed by several `_print` calls.
+struct TimestampedLogger<'a, Arch: AbstractArch> {
+    logger: Logger<Arch>,
+    now: u64,
+    at_line_start: &'a mut bool,
+}
+
+impl <'a, Arch: AbstractArch> Write for TimestampedLogger<'a, Arch> {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        for c in s.chars() {
+            if *self.at_line_start {
+                write!(self.logger, "[{:5}.{:06}] ", self.now / 1_000_000_000, self.now / 1000 % 1_000_000)?;
+                *self.at_line_start = false;
+            }
+            self.logger.write_char(c)?;
+            if c == '\n' {
+                *self.at_line_start = true;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use alloc::collections::BTreeMap;
+use proton::memory::*;
+use proton::task::TaskId;
+pub use proton::grant::{Grant, GrantId, GrantAccess};
+use crate::memory::USER_SPACE_END;
+
+/// Memory grants created by a task
+#[derive(Debug, Default)]
+pub struct GrantTable {
+    grants: BTreeMap<GrantId, Grant>,
+    next_id: usize,
+}
+
+impl GrantTable {
+    pub fn new() -> Self {
+        Self { grants: BTreeMap::new(), next_id: 0 }
+    }
+
+    /// Add a grant. The buffer is only checked against the page table when copying.
+    pub fn insert(&mut self, grant: Grant) -> Result<GrantId, ()> {
+        let end = grant.start.as_usize().checked_add(grant.size).ok_or(())?;
+        if grant.size == 0 || end > USER_SPACE_END.as_usize() || grant.access.is_empty() {
+            return Err(());
+        }
+        let id = GrantId(self.next_id);
+        self.next_id += 1;
+        self.grants.insert(id, grant);
+        Ok(id)
+    }
+
+    pub fn revoke(&mut self, id: GrantId) -> Result<(), ()> {
+        self.grants.remove(&id).map(|_| ()).ok_or(())
+    }
+
+    /// Address of `[offset, offset + size)` in a granted buffer, if `grantee` has the `access` to it
+    pub fn lookup(&self, id: GrantId, grantee: TaskId, offset: usize, size: usize, access: GrantAccess) -> Result<Address, ()> {
+        let grant = self.grants.get(&id).ok_or(())?;
+        if grant.grantee != grantee || !grant.access.contains(access) {
+            return Err(());
+        }
+        let end = offset.checked_add(size).ok_or(())?;
+        if end > grant.size {
+            return Err(());
+        }
+        Ok(grant.start + offset)
+    }
+}
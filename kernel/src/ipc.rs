@@ -1,9 +1,16 @@
 use crate::task::*;
 use proton::IPC;
+use proton::ipc::IpcError;
+use crate::scheduler::AbstractScheduler;
+use crate::memory;
+use alloc::vec;
 use crate::*;
 use crate::arch::*;
 use core::marker::PhantomData;
 
+/// Longer user log messages are truncated
+const MAX_LOG_LENGTH: usize = 4096;
+
 pub struct IPCController<K: AbstractKernel> {
     phantom: PhantomData<K>,
 }
@@ -19,6 +26,10 @@ impl <K: AbstractKernel> IPCController<K> {
             IPC::Log => log::<K>(a, b, c, d, e),
             IPC::Send => send::<K>(a, b, c, d, e),
             IPC::Receive => receive::<K>(a, b, c, d, e),
+            IPC::SendReceive => send_receive::<K>(a, b, c, d, e),
+            IPC::Notify => notify::<K>(a, b, c, d, e),
+            IPC::Yield => yield_now::<K>(a, b, c, d, e),
+            IPC::__MAX_COUNT => unreachable!(),
         }
     }
 }
@@ -40,6 +51,9 @@ impl <K: AbstractKernel> IPCController<K> {
 
 pub fn init<K: AbstractKernel>() {
     <K::Arch as AbstractArch>::Interrupt::set_handler(InterruptId::Soft, Some(box |a, b, c, d, e, f| {
+        if a >= IPC::COUNT {
+            fail::<K>(IpcError::InvalidArgument);
+        }
         let ipc: IPC = unsafe { ::core::mem::transmute(a) };
         K::global().ipc.handle(ipc, [b, c, d, e, f])
     }));
@@ -57,21 +71,73 @@ pub fn init<K: AbstractKernel>() {
 // ===   IPC Calls   ===
 // =====================
 
+/// Return to the caller with an error status
+fn fail<K: AbstractKernel>(error: IpcError) -> ! {
+    Task::<K>::current().unwrap().context.set_response_status(error.status());
+    K::global().scheduler.schedule()
+}
+
+/// Read a `T` at `address` of the caller.
+/// Kernel tasks pass pointers to kernel memory, which are trusted.
+fn read_from_caller<K: AbstractKernel, T: Copy>(address: usize) -> Result<T, IpcError> {
+    let task = Task::<K>::current().unwrap();
+    if address == 0 {
+        return Err(IpcError::InvalidArgument);
+    }
+    if !task.context.is_user_mode() {
+        return Ok(unsafe { *(address as *const T) });
+    }
+    memory::read_from_user::<K, T>(task.id(), address.into()).map_err(|_| IpcError::InvalidArgument)
+}
+
+/// Check the buffer for the received message, before any message is taken from a sender.
+/// A bad buffer must not lose a message that the sender believes is delivered.
+fn check_receive_buffer<K: AbstractKernel>(address: usize) -> Result<(), IpcError> {
+    let task = Task::<K>::current().unwrap();
+    if address == 0 {
+        return Err(IpcError::InvalidArgument);
+    }
+    if !task.context.is_user_mode() {
+        return Ok(());
+    }
+    memory::check_user_writable::<K, Message>(task.id(), address.into()).map_err(|_| IpcError::InvalidArgument)
+}
+
 pub fn log<K: AbstractKernel>(x1: usize, _x2: usize, _x3: usize, _x4: usize, _x5: usize) -> isize {
-    let string_pointer = x1 as *const &str;
-    let s: &str = unsafe { &*string_pointer };
-    crate::debug::_print::<K>(format_args!("{}", s));
+    let task = Task::<K>::current().unwrap();
+    if x1 == 0 {
+        return IpcError::InvalidArgument.status();
+    }
+    if !task.context.is_user_mode() {
+        let s: &str = unsafe { *(x1 as *const &str) };
+        crate::debug::_print::<K>(format_args!("{}", s));
+        return 0;
+    }
+    // Copy the string from user memory. A `&str` is a (pointer, length) pair.
+    let (pointer, len) = match read_from_caller::<K, (usize, usize)>(x1) {
+        Ok(s) => s,
+        Err(e) => return e.status(),
+    };
+    let mut buffer = vec![0u8; usize::min(len, MAX_LOG_LENGTH)];
+    if memory::copy_from_user::<K>(task.id(), pointer.into(), &mut buffer).is_err() {
+        return IpcError::InvalidArgument.status();
+    }
+    match ::core::str::from_utf8(&buffer) {
+        Ok(s) => crate::debug::_print::<K>(format_args!("{}", s)),
+        Err(_) => return IpcError::InvalidArgument.status(),
+    }
     0
 }
 
-fn send<K: AbstractKernel>(x1: usize, _x2: usize, _x3: usize, _x4: usize, _x5: usize) -> isize {
-    let mut msg = unsafe { (*(x1 as *const Message)).clone() };
+fn send<K: AbstractKernel>(x1: usize, x2: usize, _x3: usize, _x4: usize, _x5: usize) -> isize {
+    let mut msg = read_from_caller::<K, Message>(x1).unwrap_or_else(|e| fail::<K>(e));
     let current_task = Task::<K>::current().unwrap();
     msg.sender = current_task.id();
-    Task::<K>::send_message(msg)
+    Task::<K>::send_message(msg, x2, false)
 }
 
-fn receive<K: AbstractKernel>(x1: usize, _x2: usize, _x3: usize, _x4: usize, _x5: usize) -> isize {
+fn receive<K: AbstractKernel>(x1: usize, x2: usize, x3: usize, _x4: usize, _x5: usize) -> isize {
+    check_receive_buffer::<K>(x2).unwrap_or_else(|e| fail::<K>(e));
     let from_id = unsafe {
         let id = ::core::mem::transmute::<_, isize>(x1);
         if id < 0 {
@@ -81,5 +147,33 @@ fn receive<K: AbstractKernel>(x1: usize, _x2: usize, _x3: usize, _x4: usize, _x5
         }
     };
     debug!(K: "{:?} start receiving from {:?}", Task::<K>::current().unwrap().id(), from_id);
-    Task::<K>::receive_message(from_id)
+    Task::<K>::receive_message(from_id, x3)
+}
+
+fn send_receive<K: AbstractKernel>(x1: usize, x2: usize, x3: usize, _x4: usize, _x5: usize) -> isize {
+    check_receive_buffer::<K>(x2).unwrap_or_else(|e| fail::<K>(e));
+    let mut msg = read_from_caller::<K, Message>(x1).unwrap_or_else(|e| fail::<K>(e));
+    let current_task = Task::<K>::current().unwrap();
+    msg.sender = current_task.id();
+    Task::<K>::send_message(msg, x3, true)
+}
+
+fn notify<K: AbstractKernel>(x1: usize, x2: usize, _x3: usize, _x4: usize, _x5: usize) -> isize {
+    let current = Task::<K>::current().unwrap();
+    let status = if !current.privileges.may_send_to(TaskId(x1)) {
+        IpcError::PermissionDenied.status()
+    } else {
+        match Task::<K>::notify(TaskId(x1), x2) {
+            Ok(_) => 0,
+            Err(_) => IpcError::NoSuchTask.status(),
+        }
+    };
+    current.context.set_response_status(status);
+    // The notified task may have a higher priority
+    K::global().scheduler.schedule()
+}
+
+fn yield_now<K: AbstractKernel>(_x1: usize, _x2: usize, _x3: usize, _x4: usize, _x5: usize) -> isize {
+    Task::<K>::current().unwrap().context.set_response_status(0);
+    K::global().scheduler.yield_current_task()
 }
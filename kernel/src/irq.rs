@@ -0,0 +1,86 @@
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use core::marker::PhantomData;
+use crate::task::*;
+use crate::arch::*;
+use crate::*;
+
+#[derive(Debug, Clone, Copy)]
+struct Subscriber {
+    task: TaskId,
+    /// Notification bits sent to the task
+    bits: usize,
+}
+
+/// Device interrupts delivered to user tasks as notifications.
+///
+/// An interrupt is masked when it fires, until the subscriber acknowledges it.
+/// Level-triggered devices can't flood the kernel before the driver services them.
+pub struct IrqTable<K: AbstractKernel> {
+    subscribers: Mutex<BTreeMap<usize, Subscriber>>,
+    phantom: PhantomData<K>,
+}
+
+impl <K: AbstractKernel> IrqTable<K> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(BTreeMap::new()),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Notify `task` with `bits` when `irq` fires.
+    /// Fails if `irq` is subscribed by another task, or can't be enabled.
+    pub fn subscribe(&self, task: TaskId, irq: usize, bits: usize) -> Result<(), ()> {
+        if bits == 0 {
+            return Err(());
+        }
+        K::critical_section(|| {
+            let mut subscribers = self.subscribers.lock();
+            if subscribers.get(&irq).map(|s| s.task != task).unwrap_or(false) {
+                return Err(());
+            }
+            <K::Arch as AbstractArch>::Interrupt::enable_irq(irq)?;
+            subscribers.insert(irq, Subscriber { task, bits });
+            Ok(())
+        })
+    }
+
+    /// Unmask `irq` after it is serviced by the subscriber
+    pub fn acknowledge(&self, task: TaskId, irq: usize) -> Result<(), ()> {
+        K::critical_section(|| {
+            match self.subscribers.lock().get(&irq) {
+                Some(s) if s.task == task => <K::Arch as AbstractArch>::Interrupt::enable_irq(irq),
+                _ => Err(()),
+            }
+        })
+    }
+
+    /// Mask and release all the interrupts subscribed by an exiting task
+    pub fn unsubscribe_all(&self, task: TaskId) {
+        K::critical_section(|| {
+            self.subscribers.lock().retain(|irq, s| {
+                if s.task == task {
+                    <K::Arch as AbstractArch>::Interrupt::disable_irq(*irq);
+                    false
+                } else {
+                    true
+                }
+            });
+        })
+    }
+
+    /// Called by the interrupt handler when the device interrupt `irq` fires
+    pub fn handle_irq(&self, irq: usize) {
+        <K::Arch as AbstractArch>::Interrupt::disable_irq(irq);
+        let subscriber = self.subscribers.lock().get(&irq).copied();
+        match subscriber {
+            Some(s) => {
+                if Task::<K>::notify(s.task, s.bits).is_err() {
+                    debug!(K: "IRQ {} subscriber {:?} is gone", irq, s.task);
+                }
+            }
+            None => debug!(K: "IRQ {} has no subscriber", irq),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use crate::task::*;
+use crate::AbstractKernel;
+use proton::kernel_call::KernelCall;
+
+pub fn irq_subscribe<K: AbstractKernel>(m: &Message) {
+    let (irq, bits) = *m.get_data::<(usize, usize)>();
+    debug!(K: "{:?} subscribe IRQ {}", m.sender, irq);
+    let result = match Task::<K>::by_id(m.sender) {
+        Some(task) if task.privileges.may_use_irq(irq) => K::global().irq.subscribe(m.sender, irq, bits),
+        _ => Err(()),
+    };
+    let reply = Message::new(m.receiver, m.sender, KernelCall::IrqSubscribe as _)
+        .with_data(if result.is_ok() { 0isize } else { -1isize });
+    let _ = reply.try_send();
+}
+
+pub fn irq_ack<K: AbstractKernel>(m: &Message) {
+    let irq = *m.get_data::<usize>();
+    let result = K::global().irq.acknowledge(m.sender, irq);
+    let reply = Message::new(m.receiver, m.sender, KernelCall::IrqAck as _)
+        .with_data(if result.is_ok() { 0isize } else { -1isize });
+    let _ = reply.try_send();
+}
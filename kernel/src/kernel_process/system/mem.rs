@@ -2,15 +2,146 @@ use crate::task::*;
 use crate::arch::*;
 use proton::memory::*;
 use crate::AbstractKernel;
+use crate::memory::{self, MemoryRegion, MemoryBacking, USER_SPACE_END};
+use crate::grant::*;
+use alloc::vec;
+
+/// Safe copies go through a kernel buffer, at most this many bytes at a time
+const SAFECOPY_CHUNK_SIZE: usize = Size4K::SIZE;
 
 
 pub fn map_physical_memory<K: AbstractKernel>(m: &Message) {
     let (frame, page) = m.get_data::<(Frame, Page)>();
     debug!(K: "{:?} -> {:?}", frame, page);
-    let flags = PageFlags::PAGE_4K | PageFlags::PRESENT | PageFlags::ACCESSED;
-    <K::Arch as AbstractArch>::MemoryManager::map_user(m.sender, *page, *frame, flags);
+    // Device memory: uncached, and not owned (freed) by the task
+    let flags = PageFlags::PAGE_4K | PageFlags::PRESENT | PageFlags::ACCESSED | PageFlags::NO_CACHE;
+    // The page comes from the user message, and must not reach the page table mapping at the top
+    let in_user_space = Page::<Size4K>::is_aligned(page.start()) && page.start().as_usize() <= USER_SPACE_END.as_usize() - Size4K::SIZE;
+    let result = match Task::<K>::by_id(m.sender) {
+        Some(task) if in_user_space && task.privileges.may_map(*frame) => {
+            let region = MemoryRegion::new(page.start(), Size4K::SIZE, flags, MemoryBacking::Device);
+            task.memory_regions.lock().insert(region)
+        }
+        _ => Err(()),
+    };
+    let address = if result.is_ok() {
+        <K::Arch as AbstractArch>::MemoryManager::map_user(m.sender, *page, *frame, flags);
+        page.start()
+    } else {
+        Address::ZERO
+    };
 
     let reply_parent = Message::new(m.receiver, m.sender, 0)
-        .with_data(page.start());
-    reply_parent.send();
-}
\ No newline at end of file
+        .with_data(address);
+    let _ = reply_parent.try_send();
+}
+
+pub fn memory_map<K: AbstractKernel>(m: &Message) {
+    let (address, size, flags) = *m.get_data::<(Address, usize, PageFlags)>();
+    let result = map_anonymous_memory::<K>(m.sender, address, size, flags);
+    debug!(K: "{:?} memory_map {:?} {:?} -> {:?}", m.sender, address, size, result);
+    let reply = Message::new(m.receiver, m.sender, 0)
+        .with_data(result.unwrap_or(Address::ZERO));
+    let _ = reply.try_send();
+}
+
+pub fn memory_unmap<K: AbstractKernel>(m: &Message) {
+    let (address, size) = *m.get_data::<(Address, usize)>();
+    let result = unmap_anonymous_memory::<K>(m.sender, address, size);
+    debug!(K: "{:?} memory_unmap {:?} {:?} -> {:?}", m.sender, address, size, result);
+    let reply = Message::new(m.receiver, m.sender, 0)
+        .with_data(if result.is_ok() { 0isize } else { -1isize });
+    let _ = reply.try_send();
+}
+
+pub fn grant<K: AbstractKernel>(m: &Message) {
+    let (grantee, start, size, access) = *m.get_data::<(TaskId, Address, usize, GrantAccess)>();
+    let result = match Task::<K>::by_id(m.sender) {
+        Some(task) => task.grants.lock().insert(Grant { grantee, start, size, access }),
+        None => Err(()),
+    };
+    debug!(K: "{:?} grant {:?} {:?} {:?} to {:?} -> {:?}", m.sender, start, size, access, grantee, result);
+    let reply = Message::new(m.receiver, m.sender, 0)
+        .with_data(result.map(|id| id.0 as isize).unwrap_or(-1));
+    let _ = reply.try_send();
+}
+
+pub fn revoke_grant<K: AbstractKernel>(m: &Message) {
+    let id = *m.get_data::<GrantId>();
+    let result = match Task::<K>::by_id(m.sender) {
+        Some(task) => task.grants.lock().revoke(id),
+        None => Err(()),
+    };
+    let reply = Message::new(m.receiver, m.sender, 0)
+        .with_data(if result.is_ok() { 0isize } else { -1isize });
+    let _ = reply.try_send();
+}
+
+/// Copy between the sender and a buffer granted to it. `to_granter` is the copy direction.
+pub fn safecopy<K: AbstractKernel>(m: &Message, to_granter: bool) {
+    let (granter, grant, offset, address, size) = *m.get_data::<(TaskId, GrantId, usize, Address, usize)>();
+    let result = safecopy_impl::<K>(m.sender, granter, grant, offset, address, size, to_granter);
+    debug!(K: "{:?} safecopy {:?} {:?} {:?}+{:?} {:?} bytes -> {:?}", m.sender, granter, grant, offset, address, size, result);
+    let reply = Message::new(m.receiver, m.sender, 0)
+        .with_data(if result.is_ok() { 0isize } else { -1isize });
+    let _ = reply.try_send();
+}
+
+fn safecopy_impl<K: AbstractKernel>(grantee: TaskId, granter: TaskId, grant: GrantId, offset: usize, address: Address, size: usize, to_granter: bool) -> Result<(), ()> {
+    let access = if to_granter { GrantAccess::WRITE } else { GrantAccess::READ };
+    let granted = Task::<K>::by_id(granter).ok_or(())?.grants.lock().lookup(grant, grantee, offset, size, access)?;
+    let mut buffer = vec![0u8; usize::min(size, SAFECOPY_CHUNK_SIZE)];
+    let mut copied = 0;
+    while copied < size {
+        let chunk = &mut buffer[..usize::min(size - copied, SAFECOPY_CHUNK_SIZE)];
+        if to_granter {
+            memory::copy_from_user::<K>(grantee, address + copied, chunk)?;
+            memory::copy_to_user::<K>(granter, granted + copied, chunk)?;
+        } else {
+            memory::copy_from_user::<K>(granter, granted + copied, chunk)?;
+            memory::copy_to_user::<K>(grantee, address + copied, chunk)?;
+        }
+        copied += chunk.len();
+    }
+    Ok(())
+}
+
+fn map_anonymous_memory<K: AbstractKernel>(task_id: TaskId, address: Address, size: usize, flags: PageFlags) -> Result<Address, ()> {
+    if size == 0 || size > USER_SPACE_END.as_usize() || !Page::<Size4K>::is_aligned(address) {
+        return Err(());
+    }
+    let size = Page::<Size4K>::align_up(Address::<V>::from(size)).as_usize();
+    let task = Task::<K>::by_id(task_id).ok_or(())?;
+    let mut regions = task.memory_regions.lock();
+    let start = if address.is_zero() { regions.find_free_range(size).ok_or(())? } else { address };
+    if start.as_usize() > USER_SPACE_END.as_usize() - size {
+        return Err(());
+    }
+    // Only allow 4K user pages, with the requested protection
+    let flags = (flags & (PageFlags::NO_WRITE | PageFlags::NO_EXEC)) | PageFlags::PAGE_4K | PageFlags::PRESENT | PageFlags::ACCESSED;
+    // Frames are allocated on first access
+    regions.insert(MemoryRegion::new(start, size, flags, MemoryBacking::Anonymous))?;
+    Ok(start)
+}
+
+fn unmap_anonymous_memory<K: AbstractKernel>(task_id: TaskId, address: Address, size: usize) -> Result<(), ()> {
+    if size == 0 || size > USER_SPACE_END.as_usize() {
+        return Err(());
+    }
+    let size = Page::<Size4K>::align_up(Address::<V>::from(size)).as_usize();
+    let end = Address::<V>::from(address.as_usize().checked_add(size).ok_or(())?);
+    let task = Task::<K>::by_id(task_id).ok_or(())?;
+    let region = {
+        let mut regions = task.memory_regions.lock();
+        // Stacks, guard pages, program segments and device memory are not unmapped by the task
+        match regions.find(address) {
+            Some(r) if r.backing == MemoryBacking::Anonymous => {}
+            _ => return Err(()),
+        }
+        regions.remove(address, end)?
+    };
+    <K::Arch as AbstractArch>::MemoryManager::with_user_address_space(task_id, || {
+        memory::memory_unmap::<K>(region.start, region.end - region.start)
+    });
+    Ok(())
+}
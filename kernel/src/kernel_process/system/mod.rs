@@ -1,10 +1,12 @@
-// pub mod task;
+pub mod task;
 pub mod mem;
+pub mod irq;
 
 use core::marker::PhantomData;
 use super::KernelTask;
 use crate::AbstractKernel;
 use crate::arch::*;
+use crate::task::Task;
 use proton::task::*;
 use proton::kernel_call::KernelCall;
 
@@ -28,11 +30,42 @@ impl <K: AbstractKernel> KernelTask for System<K> {
         debug!(K: "Kernel process start");
         loop {
             debug_assert!(<K::Arch as AbstractArch>::Interrupt::is_enabled());
-            let m = Message::receive(None);
+            let m = match Message::receive(None) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
             debug!(K: "Kernel received {:?}", m);
+            if m.is_notification() {
+                continue;
+            }
+            if m.kind >= KernelCall::COUNT {
+                reply_error(&m);
+                continue;
+            }
             let kind: KernelCall = unsafe { ::core::mem::transmute(m.kind) };
+            let permitted = Task::<K>::by_id(m.sender).map(|t| t.privileges.may_call(kind)).unwrap_or(false);
+            if !permitted {
+                debug!(K: "{:?} is not allowed to call {:?}", m.sender, kind);
+                reply_error(&m);
+                continue;
+            }
             match kind {
+                KernelCall::Fork => task::fork::<K>(&m),
+                KernelCall::Exit => task::exit::<K>(&m),
+                KernelCall::Sleep => task::sleep::<K>(&m),
                 KernelCall::MapPhysicalMemory => mem::map_physical_memory::<K>(&m),
+                KernelCall::MemoryMap => mem::memory_map::<K>(&m),
+                KernelCall::MemoryUnmap => mem::memory_unmap::<K>(&m),
+                KernelCall::SetExceptionHandler => task::set_exception_handler::<K>(&m),
+                KernelCall::SetPriority => task::set_priority::<K>(&m),
+                KernelCall::GetTime => task::get_time::<K>(&m),
+                KernelCall::Grant => mem::grant::<K>(&m),
+                KernelCall::RevokeGrant => mem::revoke_grant::<K>(&m),
+                KernelCall::SafeCopyFrom => mem::safecopy::<K>(&m, false),
+                KernelCall::SafeCopyTo => mem::safecopy::<K>(&m, true),
+                KernelCall::IrqSubscribe => irq::irq_subscribe::<K>(&m),
+                KernelCall::IrqAck => irq::irq_ack::<K>(&m),
+                KernelCall::TakeExceptionReport => task::take_exception_report::<K>(&m),
                 _ => {}
             }
             //     println!("Kernel received {:?}", m);
@@ -40,4 +73,11 @@ impl <K: AbstractKernel> KernelTask for System<K> {
             // }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Reply to an invalid or unauthorized kernel call
+fn reply_error(m: &Message) {
+    let reply = Message::new(m.receiver, m.sender, m.kind)
+        .with_data(-1isize);
+    let _ = reply.try_send();
+}
@@ -1,34 +1,168 @@
 use crate::task::*;
+use crate::AbstractKernel;
 use crate::arch::*;
+use crate::scheduler::AbstractScheduler;
+use proton::kernel_call::{KernelCall, ExitReason, Time, EXCEPTION_NOTIFICATION};
+use alloc::vec::Vec;
+use spin::Mutex;
 
-pub fn fork(m: &Message) {
-    println!("fork {:?}", m.sender);
-    let parent_task = crate::task::Task::by_id(m.sender).unwrap();
-    loop {
-        debug_assert!(Target::Interrupt::is_enabled());
-        let block_to_receive_from = parent_task.block_to_receive_from.lock();
-        if block_to_receive_from.is_some() && *block_to_receive_from.as_ref().unwrap() == Some(Task::current().unwrap().id()) {
-            break
+/// Reports are dropped when the exception handler falls behind
+const MAX_EXCEPTION_REPORTS: usize = 64;
+
+/// Task to be notified when a task is killed by an exception
+static EXCEPTION_HANDLER: Mutex<Option<TaskId>> = Mutex::new(None);
+/// Tasks killed by exceptions, not yet taken by the exception handler
+static EXCEPTION_REPORTS: Mutex<Vec<(TaskId, ExitReason)>> = Mutex::new(Vec::new());
+
+pub fn fork<K: AbstractKernel>(m: &Message) {
+    debug!(K: "fork {:?}", m.sender);
+    let parent_task = Task::<K>::by_id(m.sender).unwrap();
+    // Wait until the parent is blocked for the reply,
+    // so that the child starts from the same state
+    let child_task = loop {
+        debug_assert!(<K::Arch as AbstractArch>::Interrupt::is_enabled());
+        let child_task = K::critical_section(|| {
+            let blocked = *parent_task.block_to_receive_from.lock() == Some(Some(Task::<K>::current().unwrap().id()));
+            if blocked { Some(parent_task.fork()) } else { None }
+        });
+        if let Some(child_task) = child_task {
+            break child_task
         }
-    }
-    println!("Fork task start");
-    let child_task = Target::Interrupt::uninterruptable(|| parent_task.fork());
-    println!("Fork task end");
+    };
+    debug!(K: "fork {:?} -> {:?}", parent_task.id(), child_task.id());
 
     let reply_parent = Message::new(m.receiver, parent_task.id(), 0)
         .with_data(child_task.id());
-    println!("Start send to {:?}", parent_task.id());
-    reply_parent.send();
-    println!("Finish send to {:?}", parent_task.id());
-    
-    let reply_child = Message::new(m.receiver, child_task.id(), 0);
-    reply_child.send();
+    let _ = reply_parent.try_send();
+
+    let reply_child = Message::new(m.receiver, child_task.id(), 0)
+        .with_data(0isize);
+    let _ = reply_child.try_send();
+}
+
+pub fn sleep<K: AbstractKernel>(m: &Message) {
+    let ms = *m.get_data::<usize>();
+    debug!(K: "Task {:?} sleep {} ms", m.sender, ms);
+    if ms == 0 {
+        let reply = Message::new(m.receiver, m.sender, KernelCall::Sleep as _)
+            .with_data(0isize);
+        let _ = reply.try_send();
+        return;
+    }
+    // The reply is sent by the timer, when it expires
+    K::global().timer.add(m.sender, ms);
+}
+
+pub fn get_time<K: AbstractKernel>(m: &Message) {
+    let time = Time {
+        nanos: <K::Arch as AbstractArch>::Timer::now(),
+        frequency: <K::Arch as AbstractArch>::Timer::counter_frequency(),
+    };
+    let reply = Message::new(m.receiver, m.sender, KernelCall::GetTime as _)
+        .with_data(time);
+    let _ = reply.try_send();
+}
+
+pub fn exit<K: AbstractKernel>(m: &Message) {
+    if m.sender == TaskId::KERNEL {
+        return;
+    }
+    let task = match Task::<K>::by_id(m.sender) {
+        Some(task) => task,
+        None => return,
+    };
+    // Tasks can only exit with a code. Exception reports come from `Task::abort_current`.
+    let reason = task.take_abort_reason().unwrap_or(ExitReason::Exit(*m.get_data::<isize>()));
+    debug!(K: "Task {:?} exit: {:?}", m.sender, reason);
+    // The task never receives a reply, it is released here
+    if Task::<K>::destroy(m.sender).is_err() {
+        debug!(K: "Task {:?} can't be released", m.sender);
+        return;
+    }
+    if let ExitReason::Exit(_) = reason {
+        return;
+    }
+    notify_exception_handler::<K>(m.sender, reason);
 }
 
-pub fn sleep(m: &Message) {
-    unimplemented!()
+pub fn set_exception_handler<K: AbstractKernel>(m: &Message) {
+    debug!(K: "Exception handler: {:?}", m.sender);
+    *EXCEPTION_HANDLER.lock() = Some(m.sender);
+    let reply = Message::new(m.receiver, m.sender, 0)
+        .with_data(0isize);
+    let _ = reply.try_send();
+}
+
+pub fn set_priority<K: AbstractKernel>(m: &Message) {
+    let (target, priority) = *m.get_data::<(TaskId, Priority)>();
+    // `TaskId::NULL` refers to the sender itself
+    let target = if target == TaskId::NULL { m.sender } else { target };
+    debug!(K: "Set priority of {:?} to {:?}", target, priority);
+    let status = if !may_set_priority::<K>(m.sender, target, priority) {
+        -1isize
+    } else {
+        match K::global().scheduler.set_priority(target, priority) {
+            Ok(_) => 0isize,
+            Err(_) => -1isize,
+        }
+    };
+    let reply = Message::new(m.receiver, m.sender, 0)
+        .with_data(status);
+    let _ = reply.try_send();
+}
+
+/// A task can only change its own priority, unless it is privileged to schedule other tasks.
+/// The priority is capped at the caller's current priority, or the limit in its privileges.
+fn may_set_priority<K: AbstractKernel>(sender: TaskId, target: TaskId, priority: Priority) -> bool {
+    let privileges = match Task::<K>::by_id(sender) {
+        Some(task) => &task.privileges,
+        None => return false,
+    };
+    if target != sender && !privileges.may_schedule_other_tasks() {
+        return false;
+    }
+    if priority.0 as usize >= Priority::LEVELS {
+        return false;
+    }
+    match K::global().scheduler.get_priority(sender) {
+        Some(current) => priority <= ::core::cmp::max(current, privileges.max_priority()),
+        None => false,
+    }
+}
+
+/// Queue the report and notify the handler. The handler takes the reports with `KernelCall::TakeExceptionReport`.
+fn notify_exception_handler<K: AbstractKernel>(task: TaskId, reason: ExitReason) {
+    let handler_id = match *EXCEPTION_HANDLER.lock() {
+        Some(handler_id) => handler_id,
+        None => return,
+    };
+    {
+        let mut reports = EXCEPTION_REPORTS.lock();
+        if reports.len() >= MAX_EXCEPTION_REPORTS {
+            debug!(K: "Too many exception reports, drop report for {:?}", task);
+            return;
+        }
+        reports.push((task, reason));
+    }
+    if Task::<K>::notify(handler_id, EXCEPTION_NOTIFICATION).is_err() {
+        // The handler has exited
+        *EXCEPTION_HANDLER.lock() = None;
+        EXCEPTION_REPORTS.lock().clear();
+    }
 }
 
-pub fn exit(_m: &Message) {
-    unimplemented!()
+pub fn take_exception_report<K: AbstractKernel>(m: &Message) {
+    let report = if *EXCEPTION_HANDLER.lock() != Some(m.sender) {
+        Err(())
+    } else {
+        let mut reports = EXCEPTION_REPORTS.lock();
+        Ok(if reports.is_empty() { None } else { Some(reports.remove(0)) })
+    };
+    let reply = Message::new(m.receiver, m.sender, KernelCall::TakeExceptionReport as _);
+    let reply = match report {
+        Ok(Some((task, reason))) => reply.with_data((0isize, task, reason)),
+        Ok(None) => reply.with_data(1isize),
+        Err(_) => reply.with_data(-1isize),
+    };
+    let _ = reply.try_send();
 }
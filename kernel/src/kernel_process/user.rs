@@ -3,26 +3,30 @@ use super::KernelTask;
 use crate::AbstractKernel;
 use crate::arch::*;
 use crate::memory::*;
+use crate::privilege::Privileges;
 use proton::memory::*;
 use elf_rs::*;
 
 
 
-const USER_STACK_START: Address<V> = Address::new(0x111900000);
-const USER_STACK_PAGES: usize = 4; // Too many???
-const USER_STACK_SIZE: usize = USER_STACK_PAGES * Size4K::SIZE;
-const USER_STACK_END: Address<V> = Address::new(USER_STACK_START.as_usize() + USER_STACK_SIZE);
+const USER_STACK_END: Address<V> = Address::new(0x112000000);
+const USER_STACK_SIZE: usize = 8 << 20; // 8 MiB, demand paged
+const USER_STACK_START: Address<V> = Address::new(USER_STACK_END.as_usize() - USER_STACK_SIZE);
+/// Unmapped page below the stack, to trap stack overflow
+const USER_STACK_GUARD: Address<V> = Address::new(USER_STACK_START.as_usize() - Size4K::SIZE);
 
 pub struct UserTask<K: AbstractKernel> {
     phantom: PhantomData<K>,
     elf_data: &'static [u8],
+    privileges: Option<Privileges>,
 }
 
 impl <K: AbstractKernel> UserTask<K> {
-    pub fn new(elf_data: &'static [u8]) -> Self {
+    pub fn new(elf_data: &'static [u8], privileges: Privileges) -> Self {
         Self {
             phantom: PhantomData,
             elf_data,
+            privileges: Some(privileges),
         }
     }
 
@@ -53,6 +57,7 @@ impl <K: AbstractKernel> UserTask<K> {
             debug!(K: "vaddr: {:?} .. {:?}", load_start.unwrap(), load_end.unwrap());
             let vaddr_start = Page::<Size4K>::align(load_start.unwrap());
             let vaddr_end = Page::<Size4K>::align_up(load_end.unwrap());
+            register_region::<K>(MemoryRegion::new(vaddr_start, vaddr_end - vaddr_start, PageFlags::user_code_flags(), MemoryBacking::ElfSegment));
             memory_map::<K>(vaddr_start, vaddr_end - vaddr_start, PageFlags::user_code_flags()).unwrap();
             // Copy data
             for p in elf.program_header_iter().filter(|p| p.ph.ph_type() == ProgramType::LOAD) {
@@ -71,15 +76,23 @@ impl <K: AbstractKernel> UserTask<K> {
     }
 }
 
+fn register_region<K: AbstractKernel>(region: MemoryRegion) {
+    let task = crate::task::Task::<K>::current().unwrap();
+    task.memory_regions.lock().insert(region).unwrap();
+}
+
 impl <K: AbstractKernel> KernelTask for UserTask<K> {
     fn run(&mut self) -> ! {
         debug!(K: "User task start (kernel)");
         debug!(K: "Execute user program");
         let entry = self.load_elf();
         debug!(K: "ELF File loaded");
-        // Allocate user stack
-        memory_map::<K>(USER_STACK_START, USER_STACK_PAGES << Size4K::LOG_SIZE, PageFlags::user_stack_flags()).unwrap();
-        debug!(K: "Stack memory mapped");
+        // Reserve user stack. Stack pages are mapped on first access.
+        register_region::<K>(MemoryRegion::new(USER_STACK_GUARD, Size4K::SIZE, PageFlags::empty(), MemoryBacking::Guard));
+        register_region::<K>(MemoryRegion::new(USER_STACK_START, USER_STACK_SIZE, PageFlags::user_stack_flags(), MemoryBacking::Stack));
+        debug!(K: "Stack memory reserved");
+        // Drop the kernel privileges before entering usermode
+        crate::task::Task::<K>::current().unwrap().privileges = self.privileges.take().unwrap();
         // <K::Arch as AbstractArch>::Interrupt::disable();
         debug!(K: "Start to enter usermode: {:?}", crate::task::Task::<K>::current().map(|t| t.id()));
         // Enter usermode
@@ -16,6 +16,12 @@ pub mod task;
 pub mod scheduler;
 pub mod ipc;
 pub mod kernel_process;
+pub mod smp;
+pub mod timer;
+pub mod config;
+pub mod grant;
+pub mod privilege;
+pub mod irq;
 
 use arch::*;
 use scheduler::AbstractScheduler;
@@ -23,13 +29,21 @@ use proton::lazy::Lazy;
 use ipc::IPCController;
 use kernel_process::system::System;
 use kernel_process::user::UserTask;
-use task::Task;
+use task::{Task, Priority};
+use smp::{KernelLock, MAX_CORES};
+use timer::TimerWheel;
+use config::KernelConfig;
+use irq::IrqTable;
 
 
 
 pub struct KernelGlobal<K: AbstractKernel> {
     pub scheduler: Lazy<K::Scheduler>,
     pub ipc: IPCController<K>,
+    pub kernel_lock: KernelLock,
+    pub timer: Lazy<TimerWheel<K>>,
+    pub config: Lazy<KernelConfig>,
+    pub irq: Lazy<IrqTable<K>>,
 }
 
 pub trait AbstractKernel: Sized + 'static {
@@ -40,16 +54,33 @@ pub trait AbstractKernel: Sized + 'static {
     const INITIAL_GLOBAL: KernelGlobal<Self> = KernelGlobal {
         scheduler: Lazy::new(Self::Scheduler::new),
         ipc: IPCController::new(),
+        kernel_lock: KernelLock::new(),
+        timer: Lazy::new(TimerWheel::new),
+        config: Lazy::new(KernelConfig::load::<Self>),
+        irq: Lazy::new(IrqTable::new),
     };
 
     fn global() -> &'static KernelGlobal<Self>;
 
+    /// Run `f` with interrupts disabled on this core, holding the kernel lock
+    #[inline]
+    fn critical_section<R, F: FnOnce() -> R>(f: F) -> R {
+        <Self::Arch as AbstractArch>::Interrupt::uninterruptable(|| {
+            let core = <Self::Arch as AbstractArch>::core_id();
+            Self::global().kernel_lock.acquire(core);
+            let r = f();
+            Self::global().kernel_lock.release(core);
+            r
+        })
+    }
+
     fn start() -> ! {
         debug!(Self: "Hello, Raspberry PI!");
         // Initialize kernel heap
         <Self::Arch as AbstractArch>::Heap::init();
         debug!(Self: "[kernel: kernel heap initialized]");
         debug!(Self: " - test allocation -> {}", box 233);
+        debug!(Self: "[kernel: config = {:?}]", *Self::global().config);
         <Self::Arch as AbstractArch>::Interrupt::init();
         debug!(Self: "[kernel: interrupt initialized]");
         ipc::init::<Self>();
@@ -60,18 +91,25 @@ pub trait AbstractKernel: Sized + 'static {
 
         let task = Task::<Self>::create_kernel_task(box System::<Self>::new());
         debug!(Self: "[kernel: created kernel process: {:?}]", task.id());
-        let task = Task::<Self>::create_kernel_task(Self::Arch::create_idle_task());
-        debug!(Self: "[kernel: created idle process: {:?}]", task.id());
+        // Ignore errors, the scheduler may not support priorities
+        let _ = Self::global().scheduler.set_priority(task.id(), Priority::HIGHEST);
+        // One idle task for each core
+        for _ in 0..MAX_CORES {
+            let task = Task::<Self>::create_kernel_task(Self::Arch::create_idle_task());
+            debug!(Self: "[kernel: created idle process: {:?}]", task.id());
+            let _ = Self::global().scheduler.set_priority(task.id(), Priority::IDLE);
+        }
 
         // Load init.rd
         // let initrd_address = Arch::load_initrd();
         // Start ramfs driver
-        // let task = Task::<Self>::create_kernel_task(box UserTask::<Self>::new(EMMC_ELF));
+        // let task = Task::<Self>::create_kernel_task(box UserTask::<Self>::new(EMMC_ELF, BootImage::privileges("emmc")));
         // debug!(Self: "[kernel: created emmc process: {:?}]", task.id());
 
         // Load & start init process
         let task = Task::<Self>::create_kernel_task(box UserTask::<Self>::new(
-            <Self::Arch as AbstractArch>::BootImage::get("init").unwrap()
+            <Self::Arch as AbstractArch>::BootImage::get("init").unwrap(),
+            <Self::Arch as AbstractArch>::BootImage::privileges("init"),
         ));
         debug!(Self: "[kernel: created init process: {:?}]", task.id());
 
@@ -81,6 +119,21 @@ pub trait AbstractKernel: Sized + 'static {
 
         // debug!(Self: "[kernel: created emmc process: {:?}]", task.id());
 
+        <Self::Arch as AbstractArch>::start_secondary_cores();
+        debug!(Self: "[kernel: secondary cores released]");
+
+        // The lock is released when returning to the first task
+        Self::global().kernel_lock.acquire(<Self::Arch as AbstractArch>::core_id());
+        Self::global().scheduler.schedule();
+    }
+
+    /// Entry of the secondary cores, after the boot core has initialized the kernel
+    fn start_secondary() -> ! {
+        let core = <Self::Arch as AbstractArch>::core_id();
+        debug!(Self: "[kernel: core {} started]", core);
+        <Self::Arch as AbstractArch>::Interrupt::init_core();
+        <Self::Arch as AbstractArch>::Timer::init_core();
+        Self::global().kernel_lock.acquire(core);
         Self::global().scheduler.schedule();
     }
 }
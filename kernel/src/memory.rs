@@ -1,6 +1,118 @@
+use alloc::collections::BTreeMap;
 use proton::memory::*;
 use crate::AbstractKernel;
 use crate::arch::*;
+use proton::task::TaskId;
+use core::mem::{self, MaybeUninit};
+use core::slice;
+
+/// Kernel-picked `mmap` regions are allocated from this range
+pub const USER_MMAP_START: Address<V> = Address::new(0x2000_0000_0000);
+pub const USER_MMAP_END: Address<V> = Address::new(0x7000_0000_0000);
+/// Upper limit of user address space. L4 entry 511 is reserved for the recursive page table mapping.
+pub const USER_SPACE_END: Address<V> = Address::new(0xff80_0000_0000);
+
+/// What a memory region is backed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryBacking {
+    /// Zeroed memory from `mmap`
+    Anonymous,
+    /// Loaded from the program image
+    ElfSegment,
+    Stack,
+    /// Physical device memory. Frames are not owned by the task.
+    Device,
+    /// Reserved and never mapped, e.g. the guard page below a stack
+    Guard,
+}
+
+/// A range of virtual memory owned by a task
+#[derive(Debug, Clone)]
+pub struct MemoryRegion {
+    pub start: Address,
+    pub end: Address,
+    pub flags: PageFlags,
+    pub backing: MemoryBacking,
+}
+
+impl MemoryRegion {
+    pub fn new(start: Address, size: usize, flags: PageFlags, backing: MemoryBacking) -> Self {
+        Self { start, end: start + size, flags, backing }
+    }
+
+    #[inline]
+    pub fn contains(&self, a: Address) -> bool {
+        self.start <= a && a < self.end
+    }
+
+    /// Pages in this region are allocated and zeroed on first access
+    #[inline]
+    pub fn is_demand_paged(&self) -> bool {
+        self.backing == MemoryBacking::Anonymous || self.backing == MemoryBacking::Stack
+    }
+}
+
+/// All the memory regions of a task, keyed by their start address
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRegions {
+    regions: BTreeMap<Address, MemoryRegion>,
+}
+
+impl MemoryRegions {
+    pub fn new() -> Self {
+        Self { regions: BTreeMap::new() }
+    }
+
+    pub fn overlaps(&self, start: Address, end: Address) -> bool {
+        // Only the last region that starts before `end` can overlap with [start, end)
+        match self.regions.range(..end).next_back() {
+            Some((_, r)) => r.end > start,
+            None => false,
+        }
+    }
+
+    /// Find the region containing the given address
+    pub fn find(&self, a: Address) -> Option<&MemoryRegion> {
+        let (_, r) = self.regions.range(..=a).next_back()?;
+        if r.contains(a) { Some(r) } else { None }
+    }
+
+    /// Add a region in the user address space. Fails if it overlaps with another region.
+    pub fn insert(&mut self, region: MemoryRegion) -> Result<(), ()> {
+        if region.start >= region.end || region.end > USER_SPACE_END || self.overlaps(region.start, region.end) {
+            return Err(());
+        }
+        self.regions.insert(region.start, region);
+        Ok(())
+    }
+
+    /// Remove a region. The range must match a previously inserted region.
+    pub fn remove(&mut self, start: Address, end: Address) -> Result<MemoryRegion, ()> {
+        match self.regions.get(&start) {
+            Some(r) if r.end == end => Ok(self.regions.remove(&start).unwrap()),
+            _ => Err(()),
+        }
+    }
+
+    /// Find a free range of `size` bytes in [USER_MMAP_START, USER_MMAP_END)
+    pub fn find_free_range(&self, size: usize) -> Option<Address> {
+        let mut cursor = USER_MMAP_START;
+        for r in self.regions.values() {
+            if r.end <= cursor {
+                continue;
+            }
+            if r.start >= cursor + size {
+                break;
+            }
+            cursor = r.end;
+        }
+        if cursor + size <= USER_MMAP_END {
+            Some(cursor)
+        } else {
+            None
+        }
+    }
+}
 
 // Allocate a frame and map it to the given virtual address
 pub fn memory_map<K: AbstractKernel>(address: Address, size: usize, flags: PageFlags) -> Result<Address, ()> {
@@ -12,11 +124,98 @@ pub fn memory_map<K: AbstractKernel>(address: Address, size: usize, flags: PageF
     let end_page = Page::<Size4K>::new(address + size);
     for page in start_page..end_page {
         let frame = <K::Arch as AbstractArch>::MemoryManager::alloc_frame();
+        // Zero the frame before mapping, since the page may be readonly
+        <K::Arch as AbstractArch>::MemoryManager::zero_frame::<Size4K>(frame);
+        ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
         <K::Arch as AbstractArch>::MemoryManager::map::<Size4K>(page, frame, flags);
         debug!(K: "mapped {:?}", page);
-        ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
-        unsafe { page.zero(); }::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
-        
     }
     Ok(address)
 }
+
+/// Unmap a range of pages, and release the frames
+pub fn memory_unmap<K: AbstractKernel>(address: Address, size: usize) {
+    assert!(Page::<Size4K>::is_aligned(address), "{:?} is not page aligned", address);
+    assert!(Page::<Size4K>::is_aligned(size.into()));
+    let start_page = Page::<Size4K>::new(address);
+    let end_page = Page::<Size4K>::new(address + size);
+    for page in start_page..end_page {
+        if <K::Arch as AbstractArch>::MemoryManager::translate(page.start()).is_some() {
+            <K::Arch as AbstractArch>::MemoryManager::unmap::<Size4K>(page);
+        }
+    }
+}
+
+/// Check that `[start, start + size)` is mapped with user permission in the current user address space of `task`.
+///
+/// Demand-paged and copy-on-write pages are faulted in, as if the task accessed them.
+fn check_user_range<K: AbstractKernel>(task: TaskId, start: Address, size: usize, write: bool) -> Result<(), ()> {
+    let end = start.as_usize().checked_add(size).ok_or(())?;
+    if end > USER_SPACE_END.as_usize() {
+        return Err(());
+    }
+    if size == 0 {
+        return Ok(());
+    }
+    let accessible = |page: Page<Size4K>| {
+        match <K::Arch as AbstractArch>::MemoryManager::translate(page.start()) {
+            Some((_, flags)) => !flags.contains(PageFlags::KERNEL) && !(write && flags.contains(PageFlags::NO_WRITE)),
+            None => false,
+        }
+    };
+    let start_page = Page::<Size4K>::of(start);
+    let end_page = Page::<Size4K>::new(Page::<Size4K>::align_up(Address::<V>::from(end)));
+    for page in start_page..end_page {
+        if accessible(page) {
+            continue;
+        }
+        <K::Arch as AbstractArch>::MemoryManager::handle_user_fault(task, page.start())?;
+        if !accessible(page) {
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes at `src` in the user address space of `task`.
+///
+/// The range is validated against the page table of the task, instead of trusting the user pointer.
+pub fn copy_from_user<K: AbstractKernel>(task: TaskId, src: Address, dst: &mut [u8]) -> Result<(), ()> {
+    <K::Arch as AbstractArch>::MemoryManager::with_user_address_space(task, || {
+        check_user_range::<K>(task, src, dst.len(), false)?;
+        unsafe { ::core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len()); }
+        Ok(())
+    })
+}
+
+/// Copy `src` to `dst` in the user address space of `task`.
+///
+/// The range is validated against the page table of the task, instead of trusting the user pointer.
+pub fn copy_to_user<K: AbstractKernel>(task: TaskId, dst: Address, src: &[u8]) -> Result<(), ()> {
+    <K::Arch as AbstractArch>::MemoryManager::with_user_address_space(task, || {
+        check_user_range::<K>(task, dst, src.len(), true)?;
+        unsafe { ::core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_ptr_mut::<u8>(), src.len()); }
+        Ok(())
+    })
+}
+
+/// Read a `T` at `src` in the user address space of `task`
+pub fn read_from_user<K: AbstractKernel, T: Copy>(task: TaskId, src: Address) -> Result<T, ()> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let bytes = unsafe { slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>()) };
+    copy_from_user::<K>(task, src, bytes)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Check that a `T` can be written at `dst` in the user address space of `task`
+pub fn check_user_writable<K: AbstractKernel, T>(task: TaskId, dst: Address) -> Result<(), ()> {
+    <K::Arch as AbstractArch>::MemoryManager::with_user_address_space(task, || {
+        check_user_range::<K>(task, dst, mem::size_of::<T>(), true)
+    })
+}
+
+/// Write `value` to `dst` in the user address space of `task`
+pub fn write_to_user<K: AbstractKernel, T: Copy>(task: TaskId, dst: Address, value: &T) -> Result<(), ()> {
+    let bytes = unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) };
+    copy_to_user::<K>(task, dst, bytes)
+}
@@ -0,0 +1,127 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use proton::memory::*;
+use proton::task::{TaskId, Priority};
+use proton::kernel_call::KernelCall;
+
+/// What a task is allowed to do, like the MINIX privilege structure.
+///
+/// Kernel tasks have all the privileges. User tasks get theirs from the boot image,
+/// and forked tasks inherit the privileges of the parent.
+#[derive(Debug, Clone)]
+pub struct Privileges {
+    /// Allowed `KernelCall`s, one bit for each call
+    kernel_calls: u64,
+    /// Physical ranges `[start, end)` that can be mapped by `KernelCall::MapPhysicalMemory`
+    physical_memory: Vec<(Address<P>, Address<P>)>,
+    /// Tasks that can be sent to. `None` allows all tasks.
+    send_to: Option<Vec<TaskId>>,
+    /// Device interrupts that can be subscribed. `None` allows all interrupts.
+    irqs: Option<Vec<usize>>,
+    /// Highest priority that can be set by `KernelCall::SetPriority`, if above the caller's current priority
+    max_priority: Priority,
+    /// `KernelCall::SetPriority` can change the priority of other tasks
+    schedule_other_tasks: bool,
+}
+
+impl Privileges {
+    /// Everything is allowed
+    pub fn kernel() -> Self {
+        Self {
+            kernel_calls: !0,
+            physical_memory: vec![(Address::new(0), Address::new(usize::MAX))],
+            send_to: None,
+            irqs: None,
+            max_priority: Priority::HIGHEST,
+            schedule_other_tasks: true,
+        }
+    }
+
+    /// Kernel calls that only affect the calling task, and no physical memory
+    pub fn user() -> Self {
+        Self {
+            kernel_calls: 0,
+            physical_memory: Vec::new(),
+            send_to: None,
+            irqs: Some(Vec::new()),
+            max_priority: Priority::NORMAL,
+            schedule_other_tasks: false,
+        }
+        .allow_kernel_call(KernelCall::Fork)
+        .allow_kernel_call(KernelCall::Exit)
+        .allow_kernel_call(KernelCall::Sleep)
+        .allow_kernel_call(KernelCall::MemoryMap)
+        .allow_kernel_call(KernelCall::MemoryUnmap)
+        .allow_kernel_call(KernelCall::SetPriority)
+        .allow_kernel_call(KernelCall::GetTime)
+        .allow_kernel_call(KernelCall::Grant)
+        .allow_kernel_call(KernelCall::RevokeGrant)
+        .allow_kernel_call(KernelCall::SafeCopyFrom)
+        .allow_kernel_call(KernelCall::SafeCopyTo)
+    }
+
+    pub fn allow_kernel_call(mut self, call: KernelCall) -> Self {
+        self.kernel_calls |= 1 << call as u64;
+        self
+    }
+
+    /// Allow mapping `size` bytes of physical memory at `start`.
+    /// Also allows `KernelCall::MapPhysicalMemory`.
+    pub fn allow_physical_memory(mut self, start: Address<P>, size: usize) -> Self {
+        self.physical_memory.push((start, start + size));
+        self.allow_kernel_call(KernelCall::MapPhysicalMemory)
+    }
+
+    /// Allow subscribing to the device interrupt `irq`.
+    /// Also allows `KernelCall::IrqSubscribe` and `KernelCall::IrqAck`.
+    pub fn allow_irq(mut self, irq: usize) -> Self {
+        if let Some(irqs) = self.irqs.as_mut() {
+            irqs.push(irq);
+        }
+        self.allow_kernel_call(KernelCall::IrqSubscribe)
+            .allow_kernel_call(KernelCall::IrqAck)
+    }
+
+    /// Allow raising the priority of the task up to `priority`
+    pub fn allow_priority(mut self, priority: Priority) -> Self {
+        self.max_priority = priority;
+        self
+    }
+
+    /// Only allow sending to the given tasks, and `TaskId::KERNEL`
+    pub fn restrict_send_to(mut self, tasks: &[TaskId]) -> Self {
+        self.send_to = Some(tasks.to_vec());
+        self
+    }
+
+    pub fn may_call(&self, call: KernelCall) -> bool {
+        (call as u64) < 64 && self.kernel_calls & (1 << call as u64) != 0
+    }
+
+    pub fn may_map<S: PageSize>(&self, frame: Frame<S>) -> bool {
+        self.physical_memory.iter().any(|(start, end)| *start <= frame.start() && frame.end() <= *end)
+    }
+
+    /// Kernel calls are always sent to `TaskId::KERNEL`, and are checked by `may_call`
+    pub fn may_send_to(&self, task: TaskId) -> bool {
+        match &self.send_to {
+            Some(tasks) => task == TaskId::KERNEL || tasks.contains(&task),
+            None => true,
+        }
+    }
+
+    pub fn may_use_irq(&self, irq: usize) -> bool {
+        match &self.irqs {
+            Some(irqs) => irqs.contains(&irq),
+            None => true,
+        }
+    }
+
+    pub fn max_priority(&self) -> Priority {
+        self.max_priority
+    }
+
+    pub fn may_schedule_other_tasks(&self) -> bool {
+        self.schedule_other_tasks
+    }
+}
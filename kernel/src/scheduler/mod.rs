@@ -1,8 +1,10 @@
 pub mod round_robin;
+pub mod priority;
 
 use crate::task::*;
 use crate::AbstractKernel;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use crate::arch::*;
 use core::ops::{Deref, DerefMut};
 
@@ -33,13 +35,25 @@ pub trait AbstractScheduler: Sized + 'static {
     fn new() -> Self;
 
     fn register_new_task(&self, task: Box<Task<Self::Kernel>>) -> &'static mut Task<Self::Kernel>;
-    fn remove_task(&self, id: TaskId);
+    fn remove_task(&self, id: TaskId) -> Option<Box<Task<Self::Kernel>>>;
     fn get_task_by_id(&self, id: TaskId) -> Option<&'static mut Task<Self::Kernel>>;
     fn get_current_task_id(&self) -> Option<TaskId>;
     fn get_current_task(&self) -> Option<&'static mut Task<Self::Kernel>>;
+    /// Ids of all the tasks
+    fn task_ids(&self) -> Vec<TaskId>;
 
     fn mark_task_as_ready(&self, t: &'static mut Task<Self::Kernel>);
 
+    /// Change the static priority of a task. Not all schedulers support priorities.
+    fn set_priority(&self, _id: TaskId, _priority: Priority) -> Result<(), ()> {
+        Err(())
+    }
+
+    /// The static priority of a task, if the scheduler supports priorities
+    fn get_priority(&self, _id: TaskId) -> Option<Priority> {
+        None
+    }
+
     fn unblock_sending_task(&self, id: TaskId, status: isize) {
         Self::uninterruptable(|| {
             let task = self.get_task_by_id(id).unwrap();
@@ -63,6 +77,15 @@ pub trait AbstractScheduler: Sized + 'static {
         })
     }
     
+    /// A blocked sender starts waiting for the reply (`IPC::SendReceive`), without returning to user
+    fn block_sending_task_as_receiving(&self, id: TaskId) {
+        Self::uninterruptable(|| {
+            let task = self.get_task_by_id(id).unwrap();
+            assert!(**task.scheduler_state().borrow() == RunState::Sending);
+            **task.scheduler_state().borrow_mut() = RunState::Receiving;
+        })
+    }
+
     fn block_current_task_as_sending(&self) -> ! {
         Self::uninterruptable(|| {
             let task = self.get_current_task().unwrap();
@@ -81,12 +104,23 @@ pub trait AbstractScheduler: Sized + 'static {
         })
     }
 
+    /// Put the running task back to the ready queue, and switch to the next ready task
+    fn yield_current_task(&self) -> ! {
+        Self::uninterruptable(|| {
+            let task = self.get_current_task().unwrap();
+            assert!(**task.scheduler_state().borrow() == RunState::Running);
+            self.mark_task_as_ready(task);
+            self.schedule();
+        })
+    }
+
     fn schedule(&self) -> !;
     fn timer_tick(&self);
 
+    /// Run `f` with interrupts disabled, holding the kernel lock
     #[inline]
     fn uninterruptable<R, F: FnOnce() -> R>(f: F) -> R {
-        <<Self::Kernel as AbstractKernel>::Arch as AbstractArch>::Interrupt::uninterruptable(f)
+        <Self::Kernel as AbstractKernel>::critical_section(f)
     }
 }
 
@@ -0,0 +1,331 @@
+use super::*;
+use spin::Mutex;
+use alloc::collections::{BTreeMap, LinkedList};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::arch::*;
+use crate::smp::MAX_CORES;
+use crate::*;
+use core::ops::{Deref, DerefMut};
+
+
+
+/// Ready tasks are promoted by one level every `AGING_INTERVAL` ticks
+const AGING_INTERVAL: usize = 8;
+/// Aging never promotes tasks to `Priority::HIGHEST`, which is reserved for the kernel process
+const MAX_AGED_PRIORITY: Priority = Priority(Priority::HIGHEST.0 - 1);
+
+#[derive(Debug, Clone)]
+pub struct State {
+    run_state: RunState,
+    time_slice_units: usize,
+    /// Static priority, set by `set_priority`
+    priority: Priority,
+    /// Priority boosted by aging. Reset to `priority` when the task is scheduled.
+    effective_priority: Priority,
+}
+
+impl State {
+    pub const fn new() -> Self {
+        Self {
+            run_state: RunState::Ready,
+            time_slice_units: 0,
+            priority: Priority::NORMAL,
+            effective_priority: Priority::NORMAL,
+        }
+    }
+}
+
+impl SchedulerState for State {}
+
+impl Deref for State {
+    type Target = RunState;
+    fn deref(&self) -> &Self::Target {
+        &self.run_state
+    }
+}
+
+impl DerefMut for State {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.run_state
+    }
+}
+
+impl Default for State {
+    fn default() -> Self { Self::new() }
+}
+
+/// Multi-level priority scheduler.
+///
+/// Tasks with the same priority are scheduled round-robin.
+/// A running task is preempted as soon as a higher priority task becomes ready.
+/// Ready tasks are aged to avoid starvation, except for tasks with `Priority::IDLE`.
+pub struct PriorityScheduler<K: AbstractKernel<Scheduler=Self>> {
+    /// Running task of each core
+    current_task: UnsafeCell<[Option<TaskId>; MAX_CORES]>,
+    tasks: Mutex<BTreeMap<TaskId, Box<Task<K>>>>,
+    /// One ready queue per priority level
+    task_queues: Mutex<[LinkedList<TaskId>; Priority::LEVELS]>,
+    /// Ticks of the boot core, which drives aging
+    ticks: AtomicUsize,
+}
+
+impl <K: AbstractKernel<Scheduler=Self>> AbstractScheduler for PriorityScheduler<K> {
+    type State = State;
+    type Kernel = K;
+
+    fn new() -> Self {
+        Self {
+            current_task: UnsafeCell::new([None; MAX_CORES]),
+            tasks: Mutex::new(BTreeMap::new()),
+            task_queues: Mutex::new(Default::default()),
+            ticks: AtomicUsize::new(0),
+        }
+    }
+
+    fn register_new_task(&self, task: Box<Task<K>>) -> &'static mut Task<K> {
+        Self::uninterruptable(|| {
+            let id = task.id();
+            let task_ref: &'static mut Task<K> = unsafe { &mut *((&task as &Task<K>) as *const Task<K> as usize as *mut Task<K>) };
+            self.tasks.lock().insert(id, task);
+            let state = task_ref.scheduler_state().borrow();
+            if state.run_state == RunState::Ready {
+                debug_assert!(!<K::Arch as AbstractArch>::Interrupt::is_enabled());
+                self.task_queues.lock()[state.effective_priority.0 as usize].push_back(id);
+            }
+            ::core::mem::drop(state);
+            task_ref
+        })
+    }
+
+    fn remove_task(&self, id: TaskId) -> Option<Box<Task<K>>> {
+        Self::uninterruptable(|| {
+            let task = self.tasks.lock().remove(&id)?;
+            // Remove from ready queue
+            if task.scheduler_state().borrow().run_state == RunState::Ready {
+                let level = task.scheduler_state().borrow().effective_priority.0 as usize;
+                Self::remove_from_queue(&mut self.task_queues.lock()[level], id);
+            }
+            let current_task_table = unsafe { &mut *self.current_task.get() };
+            for slot in current_task_table.iter_mut().filter(|slot| **slot == Some(id)) {
+                *slot = None;
+            }
+            Some(task)
+        })
+    }
+
+    fn get_task_by_id(&self, id: TaskId) -> Option<&'static mut Task<K>> {
+        Self::uninterruptable(|| {
+            let tasks = self.tasks.lock();
+            let task = tasks.get(&id)?;
+            let task_ref: &'static mut Task<K> = unsafe { &mut *((&task as &Task<K>) as *const Task<K> as usize as *mut Task<K>) };
+            Some(task_ref)
+        })
+    }
+
+    fn get_current_task_id(&self) -> Option<TaskId> {
+        let current_task_table = unsafe { &*self.current_task.get() };
+        current_task_table[<K::Arch as AbstractArch>::core_id()]
+    }
+
+    fn get_current_task(&self) -> Option<&'static mut Task<K>> {
+        Self::uninterruptable(|| {
+            self.get_task_by_id(self.get_current_task_id()?)
+        })
+    }
+
+    fn task_ids(&self) -> Vec<TaskId> {
+        Self::uninterruptable(|| {
+            self.tasks.lock().keys().copied().collect()
+        })
+    }
+
+    fn mark_task_as_ready(&self, task: &'static mut Task<K>) {
+        let mut state = task.scheduler_state().borrow_mut();
+        assert!(state.run_state != RunState::Ready);
+        state.run_state = RunState::Ready;
+        self.task_queues.lock()[state.effective_priority.0 as usize].push_back(task.id());
+        let is_idle = state.priority == Priority::IDLE;
+        ::core::mem::drop(state);
+        // Idle cores wait for an event, and won't pick up this task until the next tick
+        if !is_idle && self.has_idle_core() {
+            <K::Arch as AbstractArch>::wake_idle_cores();
+        }
+    }
+
+    fn get_priority(&self, id: TaskId) -> Option<Priority> {
+        Self::uninterruptable(|| {
+            let task = self.get_task_by_id(id)?;
+            let priority = task.scheduler_state().borrow().priority;
+            Some(priority)
+        })
+    }
+
+    fn set_priority(&self, id: TaskId, priority: Priority) -> Result<(), ()> {
+        if priority.0 as usize >= Priority::LEVELS {
+            return Err(());
+        }
+        Self::uninterruptable(|| {
+            let task = self.get_task_by_id(id).ok_or(())?;
+            let mut state = task.scheduler_state().borrow_mut();
+            let old_level = state.effective_priority.0 as usize;
+            state.priority = priority;
+            state.effective_priority = priority;
+            // Move to the new ready queue
+            if state.run_state == RunState::Ready {
+                let mut task_queues = self.task_queues.lock();
+                Self::remove_from_queue(&mut task_queues[old_level], id);
+                task_queues[priority.0 as usize].push_back(id);
+            }
+            Ok(())
+        })
+    }
+
+    fn schedule(&self) -> ! {
+        <K::Arch as AbstractArch>::Interrupt::disable();
+
+        let current_task = self.get_current_task();
+
+        if let Some(task) = current_task.as_ref() {
+            let state = task.scheduler_state().borrow();
+            if state.run_state == RunState::Running {
+                if !self.has_ready_task_above(state.effective_priority) {
+                    // Continue with this task
+                    ::core::mem::drop(state);
+                    unsafe { current_task.unwrap().context.return_to_user(); }
+                }
+                // A higher priority task is ready, preempt the current task
+                ::core::mem::drop(state);
+                self.enqueue_current_task_as_ready();
+            }
+        }
+
+        // Find a scheduleable task
+        let next_task = self.get_next_schedulable_task();
+
+        debug_assert!({
+            let state = next_task.scheduler_state().borrow_mut();
+            state.run_state == RunState::Ready
+        });
+        debug!(K: "Switch: {:?} -> {:?}", current_task.as_ref().map(|t| t.id()), next_task.id());
+
+        // Run next task
+        {
+            let mut state = next_task.scheduler_state().borrow_mut();
+            state.run_state = RunState::Running;
+            state.time_slice_units = K::global().config.time_slice;
+            state.effective_priority = state.priority;
+        }
+        self.set_current_task_id(next_task.id());
+        // The idle task may have stopped the periodic tick of this core
+        K::global().timer.exit_tickless_idle();
+
+        ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
+        debug!(K: "Schedule return_to_user");
+        unsafe { next_task.context.return_to_user(); }
+    }
+
+    fn timer_tick(&self) {
+        debug_assert!(!<K::Arch as AbstractArch>::Interrupt::is_enabled());
+        self.age_ready_tasks();
+
+        let current_task = self.get_current_task().unwrap();
+
+        if current_task.scheduler_state().borrow().time_slice_units == 0 {
+            panic!("time_slice_units is zero");
+        }
+
+        let mut scheduler_state = current_task.scheduler_state().borrow_mut();
+        debug_assert!(scheduler_state.run_state == RunState::Running, "Invalid state {:?} for {:?}", scheduler_state.run_state, current_task.id());
+        scheduler_state.time_slice_units -= 1;
+        if scheduler_state.time_slice_units == 0 {
+            debug!(K: "Schedule");
+            scheduler_state.time_slice_units = K::global().config.time_slice;
+            ::core::mem::drop(scheduler_state);
+            self.enqueue_current_task_as_ready();
+        } else {
+            ::core::mem::drop(scheduler_state);
+        }
+        // Switch if the time slice is used up, or a higher priority task is ready
+        self.schedule();
+    }
+}
+
+impl <K: AbstractKernel<Scheduler=Self>> PriorityScheduler<K> {
+
+    pub fn set_current_task_id(&self, id: TaskId) {
+        let current_task_table = unsafe { &mut *self.current_task.get() };
+        current_task_table[<K::Arch as AbstractArch>::core_id()] = Some(id);
+    }
+
+    pub fn enqueue_current_task_as_ready(&self) {
+        debug_assert!(!<K::Arch as AbstractArch>::Interrupt::is_enabled());
+        let task = self.get_current_task().unwrap();
+        let mut state = task.scheduler_state().borrow_mut();
+        assert!(state.run_state != RunState::Ready);
+        state.run_state = RunState::Ready;
+        self.task_queues.lock()[state.effective_priority.0 as usize].push_back(task.id());
+    }
+
+    fn remove_from_queue(queue: &mut LinkedList<TaskId>, id: TaskId) {
+        let old_queue = ::core::mem::take(queue);
+        *queue = old_queue.into_iter().filter(|t| *t != id).collect();
+    }
+
+    /// Whether any core is running a task with `Priority::IDLE`
+    fn has_idle_core(&self) -> bool {
+        let current_task_table = unsafe { &*self.current_task.get() };
+        let tasks = self.tasks.lock();
+        current_task_table.iter().filter_map(|id| tasks.get(id.as_ref()?)).any(|task| {
+            task.scheduler_state().try_borrow().map(|state| state.priority == Priority::IDLE).unwrap_or(false)
+        })
+    }
+
+    fn has_ready_task_above(&self, priority: Priority) -> bool {
+        let task_queues = self.task_queues.lock();
+        task_queues[priority.0 as usize + 1..].iter().any(|q| !q.is_empty())
+    }
+
+    /// Every `AGING_INTERVAL` ticks, promote all the ready tasks by one level, up to `MAX_AGED_PRIORITY`.
+    ///
+    /// Only the ticks of the boot core are counted, so the aging rate doesn't depend on the number of cores.
+    fn age_ready_tasks(&self) {
+        if <K::Arch as AbstractArch>::core_id() != 0 {
+            return;
+        }
+        let ticks = self.ticks.fetch_add(1, Ordering::SeqCst) + 1;
+        if ticks % AGING_INTERVAL != 0 {
+            return;
+        }
+        let mut task_queues = self.task_queues.lock();
+        // Go from high to low levels, so that each task moves up at most one level
+        for level in (Priority::IDLE.0 as usize + 1..MAX_AGED_PRIORITY.0 as usize).rev() {
+            let mut promoted = ::core::mem::take(&mut task_queues[level]);
+            for id in promoted.iter() {
+                if let Some(task) = self.get_task_by_id(*id) {
+                    task.scheduler_state().borrow_mut().effective_priority = Priority(level as u8 + 1);
+                }
+            }
+            task_queues[level + 1].append(&mut promoted);
+        }
+    }
+
+    fn get_next_schedulable_task(&self) -> &'static mut Task<K> {
+        debug_assert!(!<K::Arch as AbstractArch>::Interrupt::is_enabled());
+        let next_runnable_task = {
+            let mut task_queues = self.task_queues.lock();
+            task_queues.iter_mut().rev().find_map(|q| q.pop_front())
+        };
+        if let Some(next_runnable_task) = next_runnable_task {
+            Task::by_id(next_runnable_task).expect("task not found")
+        } else {
+            // We should at least have an `idle` task that is runnable
+            panic!("No more tasks to run!");
+        }
+    }
+}
+
+unsafe impl <K: AbstractKernel<Scheduler=Self>> Send for PriorityScheduler<K> {}
+unsafe impl <K: AbstractKernel<Scheduler=Self>> Sync for PriorityScheduler<K> {}
@@ -2,14 +2,15 @@ use super::*;
 use spin::Mutex;
 use alloc::collections::{BTreeMap, LinkedList};
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use crate::arch::*;
+use crate::smp::MAX_CORES;
 use crate::*;
 use core::ops::{Deref, DerefMut};
 
 
 
-const UNIT_TIME_SLICE: usize = 1;
 
 #[derive(Debug, Clone)]
 pub struct State {
@@ -46,7 +47,8 @@ impl Default for State {
 }
 
 pub struct RoundRobinScheduler<K: AbstractKernel<Scheduler=Self>> {
-    current_task: UnsafeCell<[Option<TaskId>; 4]>,
+    /// Running task of each core
+    current_task: UnsafeCell<[Option<TaskId>; MAX_CORES]>,
     tasks: Mutex<BTreeMap<TaskId, Box<Task<K>>>>,
     task_queue: Mutex<LinkedList<TaskId>>,
 }
@@ -57,7 +59,7 @@ impl <K: AbstractKernel<Scheduler=Self>> AbstractScheduler for RoundRobinSchedul
 
     fn new() -> Self {
         Self {
-            current_task: UnsafeCell::new([None; 4]),
+            current_task: UnsafeCell::new([None; MAX_CORES]),
             tasks: Mutex::new(BTreeMap::new()),
             task_queue: Mutex::new(LinkedList::new()),
         }
@@ -76,14 +78,21 @@ impl <K: AbstractKernel<Scheduler=Self>> AbstractScheduler for RoundRobinSchedul
         })
     }
 
-    fn remove_task(&self, id: TaskId) {
-        let _task = self.get_task_by_id(id).unwrap();
-        self.tasks.lock().remove(&id);
-        debug_assert!(!<K::Arch as AbstractArch>::Interrupt::is_enabled());
-        debug_assert!(!self.task_queue.lock().contains(&id));
-        let current_task_table = unsafe { &mut *self.current_task.get() };
-        current_task_table[0] = None;
-        unimplemented!()
+    fn remove_task(&self, id: TaskId) -> Option<Box<Task<K>>> {
+        Self::uninterruptable(|| {
+            let task = self.tasks.lock().remove(&id)?;
+            // Remove from ready queue
+            {
+                let mut task_queue = self.task_queue.lock();
+                let queue = ::core::mem::take(&mut *task_queue);
+                *task_queue = queue.into_iter().filter(|t| *t != id).collect();
+            }
+            let current_task_table = unsafe { &mut *self.current_task.get() };
+            for slot in current_task_table.iter_mut().filter(|slot| **slot == Some(id)) {
+                *slot = None;
+            }
+            Some(task)
+        })
     }
 
     fn get_task_by_id(&self, id: TaskId) -> Option<&'static mut Task<K>> {
@@ -97,7 +106,7 @@ impl <K: AbstractKernel<Scheduler=Self>> AbstractScheduler for RoundRobinSchedul
 
     fn get_current_task_id(&self) -> Option<TaskId> {
         let current_task_table = unsafe { &*self.current_task.get() };
-        current_task_table[0]
+        current_task_table[<K::Arch as AbstractArch>::core_id()]
     }
 
     fn get_current_task(&self) -> Option<&'static mut Task<K>> {
@@ -106,10 +115,18 @@ impl <K: AbstractKernel<Scheduler=Self>> AbstractScheduler for RoundRobinSchedul
         })
     }
 
+    fn task_ids(&self) -> Vec<TaskId> {
+        Self::uninterruptable(|| {
+            self.tasks.lock().keys().copied().collect()
+        })
+    }
+
     fn mark_task_as_ready(&self, task: &'static mut Task<K>) {
         assert!(task.scheduler_state().borrow().run_state != RunState::Ready);
         **task.scheduler_state().borrow_mut() = RunState::Ready;
         self.task_queue.lock().push_back(task.id());
+        // Idle cores wait for an event, and won't pick up this task until the next tick
+        <K::Arch as AbstractArch>::wake_idle_cores();
     }
 
     fn schedule(&self) -> ! {
@@ -136,9 +153,11 @@ impl <K: AbstractKernel<Scheduler=Self>> AbstractScheduler for RoundRobinSchedul
             {
                 let mut state = next_task.scheduler_state().borrow_mut();
                 state.run_state = RunState::Running;
-                state.time_slice_units = UNIT_TIME_SLICE;
+                state.time_slice_units = K::global().config.time_slice;
             }
             self.set_current_task_id(next_task.id());
+            // The idle task may have stopped the periodic tick of this core
+            K::global().timer.exit_tickless_idle();
     
             ::core::sync::atomic::fence(::core::sync::atomic::Ordering::SeqCst);
             debug!(K: "Schedule return_to_user");
@@ -160,7 +179,7 @@ impl <K: AbstractKernel<Scheduler=Self>> AbstractScheduler for RoundRobinSchedul
             scheduler_state.time_slice_units -= 1;
             if scheduler_state.time_slice_units == 0 {
                 debug!(K: "Schedule");
-                scheduler_state.time_slice_units = UNIT_TIME_SLICE;
+                scheduler_state.time_slice_units = K::global().config.time_slice;
                 ::core::mem::drop(scheduler_state);
                 self.enqueue_current_task_as_ready();
                 self.schedule();
@@ -176,7 +195,7 @@ impl <K: AbstractKernel<Scheduler=Self>> RoundRobinScheduler<K> {
 
     pub fn set_current_task_id(&self, id: TaskId) {
         let current_task_table = unsafe { &mut *self.current_task.get() };
-        current_task_table[0] = Some(id);
+        current_task_table[<K::Arch as AbstractArch>::core_id()] = Some(id);
     }
 
     // 
@@ -0,0 +1,61 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Max number of cores supported by the kernel
+pub const MAX_CORES: usize = 4;
+
+const NO_OWNER: usize = usize::MAX;
+
+/// The big kernel lock.
+///
+/// Exception handlers and critical sections on different cores are serialized by this lock.
+/// It is re-entrant on the same core, and is fully released when the core returns to user.
+pub struct KernelLock {
+    owner: AtomicUsize,
+    depth: UnsafeCell<usize>,
+}
+
+impl KernelLock {
+    pub const fn new() -> Self {
+        Self {
+            owner: AtomicUsize::new(NO_OWNER),
+            depth: UnsafeCell::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn is_held_by(&self, core: usize) -> bool {
+        self.owner.load(Ordering::Acquire) == core
+    }
+
+    pub fn acquire(&self, core: usize) {
+        if !self.is_held_by(core) {
+            while self.owner.compare_exchange_weak(NO_OWNER, core, Ordering::Acquire, Ordering::Relaxed).is_err() {
+                ::core::sync::atomic::spin_loop_hint();
+            }
+        }
+        // Only the owner can access `depth`
+        unsafe { *self.depth.get() += 1 };
+    }
+
+    pub fn release(&self, core: usize) {
+        debug_assert!(self.is_held_by(core), "Kernel lock is not held by core {}", core);
+        unsafe {
+            *self.depth.get() -= 1;
+            if *self.depth.get() == 0 {
+                self.owner.store(NO_OWNER, Ordering::Release);
+            }
+        }
+    }
+
+    /// Release the lock regardless of the nesting depth
+    pub fn release_all(&self, core: usize) {
+        if self.is_held_by(core) {
+            unsafe { *self.depth.get() = 0 };
+            self.owner.store(NO_OWNER, Ordering::Release);
+        }
+    }
+}
+
+unsafe impl Send for KernelLock {}
+unsafe impl Sync for KernelLock {}
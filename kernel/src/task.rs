@@ -4,9 +4,14 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use super::scheduler::*;
 use core::cell::RefCell;
 use crate::*;
-pub use proton::{IPC, TaskId, Message};
+pub use proton::{IPC, TaskId, Message, Priority};
+use proton::kernel_call::{KernelCall, ExitReason};
+use proton::ipc::{IpcError, NON_BLOCKING, KIND_NOTIFICATION};
 use alloc::boxed::Box;
 use crate::kernel_process::KernelTask;
+use crate::memory::MemoryRegions;
+use crate::grant::GrantTable;
+use crate::privilege::Privileges;
 
 static TASK_ID_COUNT: AtomicUsize = AtomicUsize::new(0);
 
@@ -24,7 +29,20 @@ pub struct Task<K: AbstractKernel> {
     pub context: <K::Arch as AbstractArch>::Context,
     pub block_to_receive_from: Mutex<Option<Option<TaskId>>>,
     block_to_send: Option<Message>,
+    /// Wait for the reply after the blocked message is delivered (`IPC::SendReceive`)
+    receive_after_send: bool,
+    /// Id of the timer of the current timed IPC
+    ipc_timer: Option<usize>,
+    /// Bitmap of pending notifications
+    notifications: AtomicUsize,
     blocked_senders: Mutex<BTreeSet<TaskId>>,
+    pub memory_regions: Mutex<MemoryRegions>,
+    /// Buffers shared with other tasks by safe copies
+    pub grants: Mutex<GrantTable>,
+    /// Kernel calls, physical memory and IPC targets this task is allowed to use
+    pub privileges: Privileges,
+    /// Set by `abort_current`. Only the kernel can report that a task is killed by an exception.
+    abort_reason: Option<ExitReason>,
 }
 
 impl <K: AbstractKernel> Task<K> {
@@ -33,45 +51,94 @@ impl <K: AbstractKernel> Task<K> {
         self.id
     }
 
+    /// Id of the timer of the current timed IPC
+    #[inline]
+    pub fn ipc_timer(&self) -> Option<usize> {
+        self.ipc_timer
+    }
+
+    /// The blocking IPC has completed or is restarted, remove its timer from the wheel
+    pub fn cancel_ipc_timer(&mut self) {
+        if let Some(timer) = self.ipc_timer.take() {
+            K::global().timer.cancel_ipc_timeout(timer);
+        }
+    }
+
     #[inline]
     pub fn scheduler_state(&self) -> &RefCell<<K::Scheduler as AbstractScheduler>::State> {
         &self.scheduler_state
     }
 
+    /// Receive a message, blocking for at most `timeout` ms.
+    /// See `proton::ipc::{BLOCK_FOREVER, NON_BLOCKING}`.
     #[inline]
-    pub fn receive_message(from: Option<TaskId>) -> ! {
+    pub fn receive_message(from: Option<TaskId>, timeout: usize) -> ! {
         let receiver = Task::<K>::current().unwrap();
-        // Search from blocked_senders
-        {
-            let mut blocked_senders = receiver.blocked_senders.lock();
-            let mut target_sender = None;
-            for tid in blocked_senders.iter() {
-                if from.is_none() || Some(*tid) == from {
-                    target_sender = Some(*tid);
-                }
+        receiver.cancel_ipc_timer();
+        if let Some(from) = from {
+            let status = if from == receiver.id {
+                // Receiving from itself never completes
+                Some(IpcError::Deadlock)
+            } else if Task::<K>::by_id(from).is_none() {
+                Some(IpcError::NoSuchTask)
+            } else {
+                None
+            };
+            if let Some(status) = status {
+                receiver.context.set_response_status(status.status());
+                K::global().scheduler.schedule();
             }
-            if let Some(sender_id) = target_sender {
-                // Unblock this sender
-                blocked_senders.remove(&sender_id);
-                let sender = Task::<K>::by_id(sender_id).unwrap();
-                let m = sender.block_to_send.take().unwrap();
-                K::global().scheduler.unblock_sending_task(sender_id, 0);
-                // We've received a message, return to user program
-                receiver.context.set_response_message(m);
-                receiver.context.set_response_status(0);
+        }
+        // Search from pending notifications and blocked_senders
+        if let Some(m) = receiver.take_notifications(from).or_else(|| receiver.take_message_from_blocked_senders(from)) {
+            // We've received a message, return to user program
+            receiver.context.set_response_message(m);
+            receiver.context.set_response_status(0);
+            K::global().scheduler.schedule();
+        }
+        if timeout == NON_BLOCKING {
+            receiver.context.set_response_status(IpcError::WouldBlock.status());
+            K::global().scheduler.schedule();
+        }
+        if let Some(from) = from {
+            if Self::would_deadlock(receiver.id, from) {
+                receiver.context.set_response_status(IpcError::Deadlock.status());
                 K::global().scheduler.schedule();
             }
         }
+        receiver.ipc_timer = K::global().timer.add_ipc_timeout(receiver.id, timeout);
         // Block receiver
         *receiver.block_to_receive_from.lock() = Some(from);
         K::global().scheduler.block_current_task_as_receiving();
     }
-    
+
+    /// Send a message, blocking for at most `timeout` ms.
+    /// If `receive_reply` is true, wait for the reply from the receiver, within the same timeout.
     #[inline]
-    pub fn send_message(m: Message) -> ! {
+    pub fn send_message(m: Message, timeout: usize, receive_reply: bool) -> ! {
         let sender = Task::<K>::by_id(m.sender).unwrap();
         debug_assert!(sender.id() == Task::<K>::current().unwrap().id());
-        let receiver = Task::<K>::by_id(m.receiver).unwrap();
+        sender.cancel_ipc_timer();
+        let receiver = match Task::<K>::by_id(m.receiver) {
+            Some(receiver) if receiver.id != sender.id => receiver,
+            Some(_) => {
+                // Sending to itself never completes
+                sender.context.set_response_status(IpcError::Deadlock.status());
+                K::global().scheduler.schedule()
+            }
+            None => {
+                sender.context.set_response_status(IpcError::NoSuchTask.status());
+                K::global().scheduler.schedule()
+            }
+        };
+        // Replies to a task waiting for this sender are always allowed
+        if !sender.privileges.may_send_to(receiver.id) && *receiver.block_to_receive_from.lock() != Some(Some(sender.id)) {
+            sender.context.set_response_status(IpcError::PermissionDenied.status());
+            K::global().scheduler.schedule()
+        }
+        if timeout != NON_BLOCKING {
+            sender.ipc_timer = K::global().timer.add_ipc_timeout(sender.id, timeout);
+        }
         // If the receiver is blocked for this sender, copy message & unblock the receiver
         {
             let mut block_to_receive_from_guard = receiver.block_to_receive_from.lock();
@@ -79,39 +146,185 @@ impl <K: AbstractKernel> Task<K> {
                 if block_to_receive_from.is_none() || block_to_receive_from == Some(sender.id) {
                     debug!(K: "Unblock {:?} for message {:?}", receiver.id, m);
                     *block_to_receive_from_guard = None;
+                    ::core::mem::drop(block_to_receive_from_guard);
+                    receiver.cancel_ipc_timer();
                     K::global().scheduler.unblock_receiving_task(receiver.id, 0, m);
+                    if receive_reply {
+                        sender.receive_reply(receiver.id);
+                    }
+                    sender.cancel_ipc_timer();
                     // Succesfully send the message, return to user
                     sender.context.set_response_status(0);
                     debug!(K: "Sender: {:?}", sender.scheduler_state.borrow());
-                    ::core::mem::drop(block_to_receive_from_guard);
                     K::global().scheduler.schedule()
                 }
             }
         }
+        if timeout == NON_BLOCKING {
+            sender.context.set_response_status(IpcError::WouldBlock.status());
+            K::global().scheduler.schedule();
+        }
+        if Self::would_deadlock(sender.id, receiver.id) {
+            debug!(K: "Deadlock: {:?} -> {:?}", sender.id, receiver.id);
+            sender.cancel_ipc_timer();
+            sender.context.set_response_status(IpcError::Deadlock.status());
+            K::global().scheduler.schedule();
+        }
         // Else, block the sender until message is delivered
         {
             sender.block_to_send = Some(m);
+            sender.receive_after_send = receive_reply;
             let mut blocked_senders = receiver.blocked_senders.lock();
             blocked_senders.insert(sender.id);
         }
         K::global().scheduler.block_current_task_as_sending();
     }
 
+    /// The task that this task is blocked on, if any.
+    /// A task receiving from any task is not blocked on a specific task.
+    fn blocked_on(&self) -> Option<TaskId> {
+        if let Some(m) = self.block_to_send.as_ref() {
+            return Some(m.receiver);
+        }
+        (*self.block_to_receive_from.lock()).and_then(|from| from)
+    }
+
+    /// Whether blocking task `id` on `target` would close a cycle of blocked tasks.
+    ///
+    /// Walks the chain of tasks that `target` is (transitively) blocked on, like MINIX `deadlock()`.
+    fn would_deadlock(id: TaskId, target: TaskId) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut next = Some(target);
+        while let Some(t) = next {
+            if t == id {
+                return true;
+            }
+            if !visited.insert(t) {
+                // A cycle that doesn't involve this task
+                return false;
+            }
+            next = Task::<K>::by_id(t).and_then(|t| t.blocked_on());
+        }
+        false
+    }
+
+    /// Second half of `IPC::SendReceive`. The request is delivered, wait for the reply.
+    fn receive_reply(&mut self, from: TaskId) -> ! {
+        if let Some(m) = self.take_message_from_blocked_senders(Some(from)) {
+            self.cancel_ipc_timer();
+            self.context.set_response_message(m);
+            self.context.set_response_status(0);
+            K::global().scheduler.schedule();
+        }
+        *self.block_to_receive_from.lock() = Some(Some(from));
+        K::global().scheduler.block_current_task_as_receiving();
+    }
+
+    /// Take the message from a sender blocked on this task, and unblock the sender
+    fn take_message_from_blocked_senders(&self, from: Option<TaskId>) -> Option<Message> {
+        let sender_id = {
+            let mut blocked_senders = self.blocked_senders.lock();
+            let sender_id = *blocked_senders.iter().find(|tid| from.is_none() || Some(**tid) == from)?;
+            blocked_senders.remove(&sender_id);
+            sender_id
+        };
+        let sender = Task::<K>::by_id(sender_id).unwrap();
+        let m = sender.block_to_send.take().unwrap();
+        if sender.receive_after_send {
+            // The sender keeps blocking, for the reply from this task
+            sender.receive_after_send = false;
+            *sender.block_to_receive_from.lock() = Some(Some(self.id));
+            K::global().scheduler.block_sending_task_as_receiving(sender_id);
+        } else {
+            sender.cancel_ipc_timer();
+            K::global().scheduler.unblock_sending_task(sender_id, 0);
+        }
+        Some(m)
+    }
+
+    /// Set the notification `bits` of `target`, without blocking.
+    ///
+    /// If the target is waiting for a message from any task, it is woken up with the pending notifications.
+    /// This can be called from interrupt handlers.
+    pub fn notify(target: TaskId, bits: usize) -> Result<(), ()> {
+        K::critical_section(|| {
+            let task = Task::<K>::by_id(target).ok_or(())?;
+            task.notifications.fetch_or(bits, Ordering::SeqCst);
+            let mut block_to_receive_from = task.block_to_receive_from.lock();
+            if *block_to_receive_from == Some(None) {
+                if let Some(m) = task.take_notifications(None) {
+                    *block_to_receive_from = None;
+                    ::core::mem::drop(block_to_receive_from);
+                    task.cancel_ipc_timer();
+                    K::global().scheduler.unblock_receiving_task(target, 0, m);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Take all the pending notifications, as one message from the kernel.
+    /// Notifications are only received by receiving from any task.
+    fn take_notifications(&self, from: Option<TaskId>) -> Option<Message> {
+        if from.is_some() {
+            return None;
+        }
+        let bits = self.notifications.swap(0, Ordering::SeqCst);
+        if bits == 0 {
+            return None;
+        }
+        Some(Message::new(TaskId::KERNEL, self.id, KIND_NOTIFICATION).with_data(bits))
+    }
+
+    /// The timer `timer` of a timed IPC expired. Abort the IPC with `IpcError::Timeout`, if it is still blocked.
+    pub fn ipc_timeout(id: TaskId, timer: usize) {
+        let task = match Task::<K>::by_id(id) {
+            Some(task) => task,
+            None => return,
+        };
+        if task.ipc_timer != Some(timer) {
+            // The IPC has completed
+            return;
+        }
+        task.ipc_timer = None;
+        if let Some(m) = task.block_to_send.take() {
+            task.receive_after_send = false;
+            if let Some(receiver) = Task::<K>::by_id(m.receiver) {
+                receiver.blocked_senders.lock().remove(&id);
+            }
+            K::global().scheduler.unblock_sending_task(id, IpcError::Timeout.status());
+            return;
+        }
+        let mut block_to_receive_from = task.block_to_receive_from.lock();
+        if block_to_receive_from.is_some() {
+            *block_to_receive_from = None;
+            ::core::mem::drop(block_to_receive_from);
+            K::global().scheduler.unblock_receiving_task(id, IpcError::Timeout.status(), Message::new(TaskId::NULL, id, 0));
+        }
+    }
+
     /// Fork a new task.
     /// This will duplicate the virtual memory
-    // pub fn fork(&self) -> &'static mut Task {
-    //     let id = TaskId(TASK_ID_COUNT.fetch_add(1, Ordering::SeqCst));
-    //     // Allocate task struct
-    //     let task = box Task {
-    //         id,
-    //         context: self.context.fork(),
-    //         scheduler_state: self.scheduler_state.clone(),
-    //         block_to_receive_from: Mutex::new(*self.block_to_receive_from.lock()),
-    //         block_to_send: None,
-    //         blocked_senders: Mutex::new(BTreeSet::new()),
-    //     };
-    //     GLOBAL_TASK_SCHEDULER.register_new_task(task)
-    // }
+    pub fn fork(&self) -> &'static mut Self {
+        let id = TaskId(TASK_ID_COUNT.fetch_add(1, Ordering::SeqCst));
+        // Allocate task struct
+        let task = box Task {
+            id,
+            context: self.context.fork(),
+            scheduler_state: self.scheduler_state.clone(),
+            block_to_receive_from: Mutex::new(*self.block_to_receive_from.lock()),
+            block_to_send: None,
+            receive_after_send: false,
+            ipc_timer: None,
+            notifications: AtomicUsize::new(0),
+            blocked_senders: Mutex::new(BTreeSet::new()),
+            memory_regions: Mutex::new(self.memory_regions.lock().clone()),
+            grants: Mutex::new(GrantTable::new()),
+            privileges: self.privileges.clone(),
+            abort_reason: None,
+        };
+        K::global().scheduler.register_new_task(task)
+    }
     /// Create a init task with empty p4 table
     pub fn create_kernel_task(t: Box<dyn KernelTask>) -> &'static mut Self {
         let t = box t;
@@ -124,7 +337,14 @@ impl <K: AbstractKernel> Task<K> {
             scheduler_state: RefCell::new(Default::default()),
             block_to_receive_from: Mutex::new(None),
             block_to_send: None,
+            receive_after_send: false,
+            ipc_timer: None,
+            notifications: AtomicUsize::new(0),
             blocked_senders: Mutex::new(BTreeSet::new()),
+            memory_regions: Mutex::new(MemoryRegions::new()),
+            grants: Mutex::new(GrantTable::new()),
+            privileges: Privileges::kernel(),
+            abort_reason: None,
         };
         // Add this task to the scheduler
         K::global().scheduler.register_new_task(task)
@@ -148,6 +368,71 @@ impl <K: AbstractKernel> Task<K> {
         // K::global().scheduler.register_new_task(task)
     }
 
+    /// Terminate a task and release all its resources.
+    ///
+    /// Senders blocked on this task, and receivers waiting for a message from it, are woken up with an error status.
+    /// A task running on another core can't be released, since that core is still on its kernel stack.
+    pub fn destroy(id: TaskId) -> Result<(), ()> {
+        let task = K::critical_section(|| {
+            if **Task::<K>::by_id(id)?.scheduler_state().borrow() == RunState::Running {
+                return None;
+            }
+            let task = K::global().scheduler.remove_task(id)?;
+            K::global().irq.unsubscribe_all(id);
+            // This task may be blocked on sending to another task
+            if let Some(m) = task.block_to_send.as_ref() {
+                if let Some(receiver) = Task::<K>::by_id(m.receiver) {
+                    receiver.blocked_senders.lock().remove(&id);
+                }
+            }
+            // Wake up all the senders blocked on this task
+            let blocked_senders = ::core::mem::take(&mut *task.blocked_senders.lock());
+            for sender_id in blocked_senders {
+                if let Some(sender) = Task::<K>::by_id(sender_id) {
+                    sender.block_to_send = None;
+                    sender.receive_after_send = false;
+                    K::global().scheduler.unblock_sending_task(sender_id, IpcError::DeadDestination.status());
+                }
+            }
+            // Wake up all the tasks waiting to receive from this task, e.g. `IPC::SendReceive` callers waiting for the reply
+            for receiver_id in K::global().scheduler.task_ids() {
+                let receiver = Task::<K>::by_id(receiver_id).unwrap();
+                if **receiver.scheduler_state().borrow() != RunState::Receiving {
+                    continue;
+                }
+                let mut block_to_receive_from = receiver.block_to_receive_from.lock();
+                if *block_to_receive_from != Some(Some(id)) {
+                    continue;
+                }
+                *block_to_receive_from = None;
+                ::core::mem::drop(block_to_receive_from);
+                receiver.cancel_ipc_timer();
+                K::global().scheduler.unblock_receiving_task(receiver_id, IpcError::DeadDestination.status(), Message::new(TaskId::NULL, receiver_id, 0));
+            }
+            Some(task)
+        });
+        // Release address space and kernel stack
+        ::core::mem::drop(task.ok_or(())?);
+        Ok(())
+    }
+
+    /// Kill the current task from an exception handler.
+    ///
+    /// The task can't be released while we are still on its kernel stack.
+    /// Instead, it is restarted in kernel mode to send an `Exit` message to the kernel process.
+    pub fn abort_current(reason: ExitReason) -> ! {
+        let task = Task::<K>::current().unwrap();
+        debug!(K: "Abort {:?}: {:?}", task.id(), reason);
+        task.abort_reason = Some(reason);
+        task.context.restart_in_kernel(exit_entry as _, 0usize as *mut ());
+        K::global().scheduler.schedule()
+    }
+
+    /// Why the task is exiting, if it was killed by an exception
+    pub fn take_abort_reason(&mut self) -> Option<ExitReason> {
+        self.abort_reason.take()
+    }
+
     pub fn by_id(id: TaskId) -> Option<&'static mut Self> {
         K::global().scheduler.get_task_by_id(id)
     }
@@ -171,4 +456,14 @@ impl <K: AbstractKernel> Eq for Task<K> {}
 extern fn entry(t: *mut Box<dyn KernelTask>) -> ! {
     let mut t: Box<Box<dyn KernelTask>> = unsafe { Box::from_raw(t) };
     t.run()
+}
+
+/// The exit reason is kept in `Task::abort_reason`
+extern fn exit_entry(_: *mut ()) -> ! {
+    let m = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::Exit as _)
+        .with_data(-1isize);
+    // The kernel process never replies to an exited task.
+    // Waiting for the reply atomically parks this task, so it is not running on any core when it is released.
+    let _ = m.send_receive();
+    unreachable!()
 }
\ No newline at end of file
@@ -0,0 +1,223 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use proton::kernel_call::KernelCall;
+use proton::ipc::BLOCK_FOREVER;
+use crate::task::*;
+use crate::smp::MAX_CORES;
+use crate::scheduler::AbstractScheduler;
+use crate::arch::*;
+use crate::*;
+
+const WHEEL_SLOTS: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+enum TimeoutKind {
+    /// Reply to `KernelCall::Sleep`, sent in the IPC with the given timer (see `Task::ipc_timer`)
+    Sleep(Option<usize>),
+    /// Abort a timed IPC, see `Task::ipc_timeout`
+    Ipc(usize),
+}
+
+#[derive(Debug)]
+struct Timeout {
+    deadline: usize,
+    task: TaskId,
+    kind: TimeoutKind,
+}
+
+/// A hashed timer wheel, for tasks blocked in `KernelCall::Sleep` or in a timed IPC.
+///
+/// Time is measured in timer ticks, and the wheel is driven by the boot core.
+pub struct TimerWheel<K: AbstractKernel> {
+    ticks: AtomicUsize,
+    /// Tick at which the boot core wakes up from tickless idle, or zero if its tick is periodic
+    idle_wakeup: AtomicUsize,
+    /// Whether the periodic tick of each core is stopped by the idle task
+    tickless: [AtomicBool; MAX_CORES],
+    next_ipc_timer: AtomicUsize,
+    slots: Mutex<Vec<Vec<Timeout>>>,
+    phantom: PhantomData<K>,
+}
+
+impl <K: AbstractKernel> TimerWheel<K> {
+    pub fn new() -> Self {
+        Self {
+            ticks: AtomicUsize::new(0),
+            idle_wakeup: AtomicUsize::new(0),
+            tickless: Default::default(),
+            next_ipc_timer: AtomicUsize::new(0),
+            slots: Mutex::new((0..WHEEL_SLOTS).map(|_| Vec::new()).collect()),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Number of ticks since boot
+    #[inline]
+    pub fn ticks(&self) -> usize {
+        self.ticks.load(Ordering::SeqCst)
+    }
+
+    /// Convert milliseconds to ticks, rounded up
+    pub fn ms_to_ticks(ms: usize) -> usize {
+        let frequency = K::global().config.tick_frequency;
+        ms.saturating_mul(frequency).saturating_add(999) / 1000
+    }
+
+    /// Wake up `task` with a reply from the kernel, after `ms` milliseconds
+    pub fn add(&self, task: TaskId, ms: usize) {
+        K::critical_section(|| {
+            // The reply is dropped if this IPC is aborted by its own timeout
+            let ipc_timer = Task::<K>::by_id(task).and_then(|t| t.ipc_timer());
+            let deadline = self.deadline(ms);
+            self.insert(Timeout { deadline, task, kind: TimeoutKind::Sleep(ipc_timer) });
+        })
+    }
+
+    /// Abort the blocking IPC of `task` after `ms` milliseconds.
+    /// Returns the id of the timer, or `None` for `BLOCK_FOREVER`.
+    pub fn add_ipc_timeout(&self, task: TaskId, ms: usize) -> Option<usize> {
+        if ms == BLOCK_FOREVER {
+            return None;
+        }
+        K::critical_section(|| {
+            let deadline = self.deadline(ms);
+            // The slot is part of the timer id, so the timer can be cancelled without searching the whole wheel
+            let timer = self.next_ipc_timer.fetch_add(1, Ordering::SeqCst) * WHEEL_SLOTS + deadline % WHEEL_SLOTS;
+            self.insert(Timeout { deadline, task, kind: TimeoutKind::Ipc(timer) });
+            Some(timer)
+        })
+    }
+
+    /// Remove the timer of an IPC that has completed
+    pub fn cancel_ipc_timeout(&self, timer: usize) {
+        K::critical_section(|| {
+            self.slots.lock()[timer % WHEEL_SLOTS].retain(|t| !matches!(t.kind, TimeoutKind::Ipc(id) if id == timer));
+        })
+    }
+
+    /// Deadline of a timeout after `ms` milliseconds, at least one tick from now
+    fn deadline(&self, ms: usize) -> usize {
+        self.ticks() + usize::max(Self::ms_to_ticks(ms), 1)
+    }
+
+    fn insert(&self, timeout: Timeout) {
+        let mut slots = self.slots.lock();
+        // The boot core is idle and would miss this deadline, wake it up to reprogram its timer
+        let idle_wakeup = self.idle_wakeup.load(Ordering::SeqCst);
+        if idle_wakeup != 0 && timeout.deadline < idle_wakeup {
+            <K::Arch as AbstractArch>::wake_idle_cores();
+        }
+        slots[timeout.deadline % WHEEL_SLOTS].push(timeout);
+    }
+
+    /// Advance the wheel by `ticks` ticks, and wake up all the expired tasks.
+    ///
+    /// More than one tick may have passed, if the periodic tick was stopped on an idle core.
+    pub fn advance(&self, ticks: usize) {
+        debug_assert!(!<K::Arch as AbstractArch>::Interrupt::is_enabled());
+        // The tick of the boot core is periodic again
+        self.idle_wakeup.store(0, Ordering::SeqCst);
+        if ticks == 0 {
+            return;
+        }
+        let now = self.ticks.fetch_add(ticks, Ordering::SeqCst) + ticks;
+        // Every slot is visited at most once
+        let first = now + 1 - usize::min(ticks, WHEEL_SLOTS);
+        let expired = {
+            let mut slots = self.slots.lock();
+            let mut expired = Vec::new();
+            for t in first..=now {
+                let slot = &mut slots[t % WHEEL_SLOTS];
+                let (mut e, pending): (Vec<Timeout>, Vec<Timeout>) = ::core::mem::take(slot).into_iter().partition(|t| t.deadline <= now);
+                *slot = pending;
+                expired.append(&mut e);
+            }
+            expired
+        };
+        for timeout in expired {
+            match timeout.kind {
+                TimeoutKind::Sleep(ipc_timer) => Self::wake(timeout.task, ipc_timer),
+                TimeoutKind::Ipc(timer) => Task::<K>::ipc_timeout(timeout.task, timer),
+            }
+        }
+    }
+
+    /// The earliest deadline in the wheel
+    pub fn next_deadline(&self) -> Option<usize> {
+        Self::earliest(&self.slots.lock())
+    }
+
+    fn earliest(slots: &[Vec<Timeout>]) -> Option<usize> {
+        slots.iter().flat_map(|slot| slot.iter()).map(|t| t.deadline).min()
+    }
+
+    /// Stop the periodic tick on this core, until the next timeout or the end of a time slice.
+    ///
+    /// Called by the idle task, when there is nothing else to run on this core.
+    pub fn enter_tickless_idle(&self) {
+        let config = &K::global().config;
+        if !config.tickless_idle {
+            return;
+        }
+        <K::Arch as AbstractArch>::Interrupt::uninterruptable(|| {
+            let ticks = config.time_slice;
+            let core = <K::Arch as AbstractArch>::core_id();
+            self.tickless[core].store(true, Ordering::SeqCst);
+            // Only the boot core drives the wheel
+            if core != 0 {
+                <K::Arch as AbstractArch>::Timer::set_next_tick(ticks);
+                return;
+            }
+            // Hold the wheel, so a new timeout either is seen here or wakes this core up (see `insert`)
+            let slots = self.slots.lock();
+            let ticks = match Self::earliest(&slots) {
+                Some(deadline) => usize::max(usize::min(ticks, deadline.saturating_sub(self.ticks())), 1),
+                None => ticks,
+            };
+            self.idle_wakeup.store(self.ticks() + ticks, Ordering::SeqCst);
+            <K::Arch as AbstractArch>::Timer::set_next_tick(ticks);
+        })
+    }
+
+    /// Restore the periodic tick of this core, if it was stopped by the idle task.
+    ///
+    /// Called by the scheduler when it switches to a task, since the idle task may be woken up by other interrupts or events.
+    pub fn exit_tickless_idle(&self) {
+        debug_assert!(!<K::Arch as AbstractArch>::Interrupt::is_enabled());
+        let core = <K::Arch as AbstractArch>::core_id();
+        if !self.tickless[core].swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if core == 0 {
+            self.idle_wakeup.store(0, Ordering::SeqCst);
+        }
+        // Fires immediately if ticks were skipped, so the wheel catches up
+        <K::Arch as AbstractArch>::Timer::set_next_tick(1);
+    }
+
+    /// Send the reply of `KernelCall::Sleep`.
+    /// Like other kernel replies, it is dropped if the task is no longer waiting for it.
+    fn wake(id: TaskId, ipc_timer: Option<usize>) {
+        let task = match Task::<K>::by_id(id) {
+            Some(task) => task,
+            // The task has exited
+            None => return,
+        };
+        if task.ipc_timer() != ipc_timer {
+            // The sleep was aborted by its IPC timeout
+            return;
+        }
+        let mut block_to_receive_from = task.block_to_receive_from.lock();
+        if *block_to_receive_from != Some(Some(TaskId::KERNEL)) {
+            return;
+        }
+        *block_to_receive_from = None;
+        ::core::mem::drop(block_to_receive_from);
+        task.cancel_ipc_timer();
+        let reply = Message::new(TaskId::KERNEL, id, KernelCall::Sleep as _)
+            .with_data(0isize);
+        K::global().scheduler.unblock_receiving_task(id, 0, reply);
+    }
+}
@@ -14,8 +14,9 @@ macro_rules! driver_entry {
         pub extern fn _start(_argc: isize, _argv: *const *const u8) -> isize {
             let mut driver = <$driver as $crate::driver::Driver>::new();
             loop {
-                let m = Message::receive(None);
-                driver.handle_message(&m);
+                if let Ok(m) = Message::receive(None) {
+                    driver.handle_message(&m);
+                }
             }
         }
     };
@@ -0,0 +1,312 @@
+//! Decoder for the AArch64 Exception Syndrome Register (ESR_ELx).
+//!
+//! This is pure bit manipulation with no register access,
+//! so it can be built and tested on the host (`cargo test -p proton`).
+
+use core::fmt;
+
+/// Exception class, `ESR_ELx.EC`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionClass {
+    Unknown,
+    WFxTrap,
+    FPAccessTrap,
+    IllegalExecutionState,
+    SVCAArch64,
+    SystemRegisterTrap,
+    InstructionAbortLowerEL,
+    InstructionAbortSameEL,
+    PCAlignmentFault,
+    DataAbortLowerEL,
+    DataAbortSameEL,
+    SPAlignmentFault,
+    FPException,
+    SError,
+    BreakpointLowerEL,
+    BreakpointSameEL,
+    SoftwareStepLowerEL,
+    SoftwareStepSameEL,
+    WatchpointLowerEL,
+    WatchpointSameEL,
+    BRK,
+    /// Any other (e.g. AArch32 or EL2/EL3) class
+    Other(u8),
+}
+
+impl ExceptionClass {
+    pub const fn from_bits(ec: u8) -> Self {
+        match ec {
+            0b000000 => Self::Unknown,
+            0b000001 => Self::WFxTrap,
+            0b000111 => Self::FPAccessTrap,
+            0b001110 => Self::IllegalExecutionState,
+            0b010101 => Self::SVCAArch64,
+            0b011000 => Self::SystemRegisterTrap,
+            0b100000 => Self::InstructionAbortLowerEL,
+            0b100001 => Self::InstructionAbortSameEL,
+            0b100010 => Self::PCAlignmentFault,
+            0b100100 => Self::DataAbortLowerEL,
+            0b100101 => Self::DataAbortSameEL,
+            0b100110 => Self::SPAlignmentFault,
+            0b101100 => Self::FPException,
+            0b101111 => Self::SError,
+            0b110000 => Self::BreakpointLowerEL,
+            0b110001 => Self::BreakpointSameEL,
+            0b110010 => Self::SoftwareStepLowerEL,
+            0b110011 => Self::SoftwareStepSameEL,
+            0b110100 => Self::WatchpointLowerEL,
+            0b110101 => Self::WatchpointSameEL,
+            0b111100 => Self::BRK,
+            v => Self::Other(v),
+        }
+    }
+
+    pub fn is_instruction_abort(&self) -> bool {
+        *self == Self::InstructionAbortLowerEL || *self == Self::InstructionAbortSameEL
+    }
+
+    pub fn is_data_abort(&self) -> bool {
+        *self == Self::DataAbortLowerEL || *self == Self::DataAbortSameEL
+    }
+}
+
+/// Data/Instruction fault status code, `ISS.DFSC` or `ISS.IFSC`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultStatus {
+    AddressSize { level: u8 },
+    Translation { level: u8 },
+    AccessFlag { level: u8 },
+    Permission { level: u8 },
+    SynchronousExternal,
+    SynchronousExternalOnWalk { level: u8 },
+    Parity,
+    ParityOnWalk { level: u8 },
+    Alignment,
+    TLBConflict,
+    Other(u8),
+}
+
+impl FaultStatus {
+    pub const fn from_bits(fsc: u8) -> Self {
+        let level = fsc & 0b11;
+        match fsc & 0b111111 {
+            0b000000..=0b000011 => Self::AddressSize { level },
+            0b000100..=0b000111 => Self::Translation { level },
+            0b001001..=0b001011 => Self::AccessFlag { level },
+            0b001101..=0b001111 => Self::Permission { level },
+            0b010000 => Self::SynchronousExternal,
+            0b010100..=0b010111 => Self::SynchronousExternalOnWalk { level },
+            0b011000 => Self::Parity,
+            0b011100..=0b011111 => Self::ParityOnWalk { level },
+            0b100001 => Self::Alignment,
+            0b110000 => Self::TLBConflict,
+            v => Self::Other(v),
+        }
+    }
+
+    /// Faults that can be resolved by updating the page table (e.g. CoW or demand paging)
+    pub fn is_paging_fault(&self) -> bool {
+        match self {
+            Self::Translation { .. } | Self::AccessFlag { .. } | Self::Permission { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Error severity of an SError interrupt, `ISS.AET`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SErrorSeverity {
+    Uncontainable,
+    Unrecoverable,
+    Restartable,
+    Recoverable,
+    Corrected,
+    /// Implementation defined syndrome (`ISS.IDS` is set), or a reserved encoding
+    Unknown,
+}
+
+/// A raw `ESR_ELx` value
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Esr(pub u32);
+
+impl Esr {
+    const ISS_MASK: u32 = (1 << 25) - 1;
+    const IL: u32 = 1 << 25;
+    const WNR: u32 = 1 << 6;
+    const FNV: u32 = 1 << 10;
+    const IDS: u32 = 1 << 24;
+
+    pub const fn new(v: u32) -> Self {
+        Self(v)
+    }
+
+    #[inline]
+    pub const fn ec(&self) -> u8 {
+        ((self.0 >> 26) & 0b111111) as u8
+    }
+
+    #[inline]
+    pub const fn class(&self) -> ExceptionClass {
+        ExceptionClass::from_bits(self.ec())
+    }
+
+    /// Instruction syndrome
+    #[inline]
+    pub const fn iss(&self) -> u32 {
+        self.0 & Self::ISS_MASK
+    }
+
+    /// Whether the trapped instruction is 32-bit
+    #[inline]
+    pub const fn is_32bit_instruction(&self) -> bool {
+        self.0 & Self::IL != 0
+    }
+
+    /// Whether this is an instruction or data abort
+    #[inline]
+    pub fn is_abort(&self) -> bool {
+        let class = self.class();
+        class.is_instruction_abort() || class.is_data_abort()
+    }
+
+    /// Fault status of an instruction or data abort
+    pub fn fault_status(&self) -> Option<FaultStatus> {
+        if self.is_abort() {
+            Some(FaultStatus::from_bits((self.iss() & 0b111111) as u8))
+        } else {
+            None
+        }
+    }
+
+    /// Whether a data abort is caused by a write (`WnR`)
+    pub fn is_write(&self) -> bool {
+        self.class().is_data_abort() && self.iss() & Self::WNR != 0
+    }
+
+    /// Whether `FAR_ELx` holds the faulting address. `FnV` is only valid for aborts.
+    pub fn is_far_valid(&self) -> bool {
+        self.is_abort() && self.iss() & Self::FNV == 0
+    }
+
+    /// Severity of an SError interrupt
+    pub fn serror_severity(&self) -> Option<SErrorSeverity> {
+        if self.class() != ExceptionClass::SError {
+            return None;
+        }
+        if self.iss() & Self::IDS != 0 {
+            return Some(SErrorSeverity::Unknown);
+        }
+        Some(match (self.iss() >> 10) & 0b111 {
+            0b000 => SErrorSeverity::Uncontainable,
+            0b001 => SErrorSeverity::Unrecoverable,
+            0b010 => SErrorSeverity::Restartable,
+            0b011 => SErrorSeverity::Recoverable,
+            0b110 => SErrorSeverity::Corrected,
+            _ => SErrorSeverity::Unknown,
+        })
+    }
+
+    /// Immediate value of `svc` or `brk`
+    pub fn immediate(&self) -> Option<u16> {
+        match self.class() {
+            ExceptionClass::SVCAArch64 | ExceptionClass::BRK => Some((self.iss() & 0xffff) as u16),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ESR(0x{:x}: {:?}, ISS=0x{:x}", self.0, self.class(), self.iss())?;
+        if let Some(status) = self.fault_status() {
+            write!(f, ", {:?}", status)?;
+            if self.class().is_data_abort() {
+                write!(f, ", {}", if self.is_write() { "write" } else { "read" })?;
+            }
+            if !self.is_far_valid() {
+                write!(f, ", FAR not valid")?;
+            }
+        }
+        if let Some(severity) = self.serror_severity() {
+            write!(f, ", {:?}", severity)?;
+        }
+        if let Some(imm) = self.immediate() {
+            write!(f, ", #{}", imm)?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exception_class() {
+        assert_eq!(Esr::new(0x5600_0005).class(), ExceptionClass::SVCAArch64);
+        assert_eq!(Esr::new(0x9200_0047).class(), ExceptionClass::DataAbortLowerEL);
+        assert_eq!(Esr::new(0x8600_004e).class(), ExceptionClass::InstructionAbortSameEL);
+        assert_eq!(Esr::new(0xbe00_0000).class(), ExceptionClass::SError);
+        assert_eq!(Esr::new(0xe800_0000).class(), ExceptionClass::Other(0b111010));
+        assert_eq!(Esr::new(0x0000_0000).class(), ExceptionClass::Unknown);
+        assert!(Esr::new(0x9200_0047).is_abort());
+        assert!(!Esr::new(0x5600_0005).is_abort());
+    }
+
+    #[test]
+    fn instruction_length() {
+        assert!(Esr::new(0x9200_0047).is_32bit_instruction());
+        assert!(!Esr::new(0x9000_0047).is_32bit_instruction());
+    }
+
+    #[test]
+    fn instruction_syndrome() {
+        assert_eq!(Esr::new(0x9200_0047).iss(), 0x47);
+        assert_eq!(Esr::new(0x5600_0005).iss(), 5);
+        assert_eq!(Esr::new(0x5600_0005).immediate(), Some(5));
+        assert_eq!(Esr::new(0xf200_03e8).immediate(), Some(1000));
+        assert_eq!(Esr::new(0x9200_0047).immediate(), None);
+    }
+
+    #[test]
+    fn fault_status() {
+        assert_eq!(Esr::new(0x9200_0047).fault_status(), Some(FaultStatus::Translation { level: 3 }));
+        assert_eq!(Esr::new(0x8600_004e).fault_status(), Some(FaultStatus::Permission { level: 2 }));
+        assert_eq!(Esr::new(0x9200_0009).fault_status(), Some(FaultStatus::AccessFlag { level: 1 }));
+        assert_eq!(Esr::new(0x9200_0021).fault_status(), Some(FaultStatus::Alignment));
+        assert_eq!(Esr::new(0x9200_0010).fault_status(), Some(FaultStatus::SynchronousExternal));
+        assert_eq!(Esr::new(0x5600_0005).fault_status(), None);
+        assert!(FaultStatus::Translation { level: 0 }.is_paging_fault());
+        assert!(!FaultStatus::Alignment.is_paging_fault());
+    }
+
+    #[test]
+    fn write_not_read() {
+        assert!(Esr::new(0x9200_0047).is_write());
+        assert!(!Esr::new(0x9200_0007).is_write());
+        // WnR is only defined for data aborts
+        assert!(!Esr::new(0x8600_004e).is_write());
+    }
+
+    #[test]
+    fn far_not_valid() {
+        assert!(Esr::new(0x9200_0047).is_far_valid());
+        assert!(!Esr::new(0x9200_0410).is_far_valid());
+        // FnV is only defined for aborts
+        assert!(!Esr::new(0x5600_0405).is_far_valid());
+    }
+
+    #[test]
+    fn serror_severity() {
+        assert_eq!(Esr::new(0xbe00_0000).serror_severity(), Some(SErrorSeverity::Uncontainable));
+        assert_eq!(Esr::new(0xbe00_0400).serror_severity(), Some(SErrorSeverity::Unrecoverable));
+        assert_eq!(Esr::new(0xbe00_0800).serror_severity(), Some(SErrorSeverity::Restartable));
+        assert_eq!(Esr::new(0xbe00_0c00).serror_severity(), Some(SErrorSeverity::Recoverable));
+        assert_eq!(Esr::new(0xbe00_1800).serror_severity(), Some(SErrorSeverity::Corrected));
+        // Reserved encoding
+        assert_eq!(Esr::new(0xbe00_1000).serror_severity(), Some(SErrorSeverity::Unknown));
+        // Implementation defined syndrome
+        assert_eq!(Esr::new(0xbf00_0000).serror_severity(), Some(SErrorSeverity::Unknown));
+        assert_eq!(Esr::new(0x9200_0047).serror_severity(), None);
+    }
+}
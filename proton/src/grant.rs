@@ -0,0 +1,29 @@
+use crate::*;
+
+/// Id of a memory grant, local to the granting task
+#[repr(transparent)]
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct GrantId(pub usize);
+
+bitflags! {
+    /// What the grantee is allowed to do with a granted buffer
+    pub struct GrantAccess: usize {
+        /// The grantee can copy from the buffer
+        const READ  = 0b1 << 0;
+        /// The grantee can copy to the buffer
+        const WRITE = 0b1 << 1;
+    }
+}
+
+/// A buffer in the address space of the granting task, shared with one grantee.
+///
+/// The grantee never maps the buffer. It asks the kernel to copy from or to it,
+/// with `KernelCall::safecopy_from` or `KernelCall::safecopy_to`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Grant {
+    pub grantee: TaskId,
+    pub start: Address,
+    pub size: usize,
+    pub access: GrantAccess,
+}
@@ -2,19 +2,79 @@ use crate::*;
 
 pub use super::Message;
 
+/// Errors of IPC calls. The kernel returns them to the caller as negative status codes.
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    /// The receiver exited before the message was delivered
+    DeadDestination = -1,
+    /// A non-blocking IPC can't complete immediately
+    WouldBlock = -2,
+    /// A timed IPC didn't complete before the deadline
+    Timeout = -3,
+    /// The task id doesn't refer to a living task
+    NoSuchTask = -4,
+    /// The IPC would block forever
+    Deadlock = -5,
+    /// The caller is not allowed to perform this IPC
+    PermissionDenied = -6,
+    /// Invalid IPC number, or invalid argument
+    InvalidArgument = -7,
+    /// The kernel returned an unknown status code
+    Unknown = isize::MIN,
+}
+
+impl IpcError {
+    /// Status code returned by the kernel
+    #[inline]
+    pub const fn status(self) -> isize {
+        self as isize
+    }
+
+    #[inline]
+    pub fn from_status(status: isize) -> Result<(), Self> {
+        match status {
+            0 => Ok(()),
+            -1 => Err(Self::DeadDestination),
+            -2 => Err(Self::WouldBlock),
+            -3 => Err(Self::Timeout),
+            -4 => Err(Self::NoSuchTask),
+            -5 => Err(Self::Deadlock),
+            -6 => Err(Self::PermissionDenied),
+            -7 => Err(Self::InvalidArgument),
+            _ => Err(Self::Unknown),
+        }
+    }
+}
+
+/// Message kind of a notification.
+/// Pending notifications are received as one message from `TaskId::KERNEL`, with the notification bits as data.
+pub const KIND_NOTIFICATION: usize = usize::MAX;
+
+/// Timeout (in milliseconds) of a blocking IPC, that never gives up
+pub const BLOCK_FOREVER: usize = usize::MAX;
+/// Timeout of a non-blocking IPC, that returns `IpcError::WouldBlock` instead of blocking
+pub const NON_BLOCKING: usize = 0;
+
 #[repr(usize)]
 pub enum IPC {
     Log = 0,
     Send,
     Receive,
+    /// Send a message, and wait for the reply from the receiver (like MINIX `SENDREC`)
+    SendReceive,
+    /// Set notification bits of a task, without blocking
+    Notify,
+    /// Give up the processor, if other tasks are ready
+    Yield,
 
-    // #[allow(non_camel_case_types)]
-    // __MAX_COUNT,
+    #[allow(non_camel_case_types)]
+    __MAX_COUNT,
 }
 
 impl IPC {
-    // pub const COUNT: usize = Self::__MAX_COUNT as _;
-    
+    pub const COUNT: usize = Self::__MAX_COUNT as _;
+
     #[inline]
     pub fn log(message: &str) {
         unsafe {
@@ -23,16 +83,83 @@ impl IPC {
     }
 
     #[inline]
-    pub fn send(mut m: Message) {
+    pub fn send(m: Message) -> Result<(), IpcError> {
+        IpcError::from_status(Self::send_with_timeout(m, BLOCK_FOREVER))
+    }
+
+    /// Send a message only if the receiver is waiting for it
+    #[inline]
+    pub fn try_send(m: Message) -> Result<(), IpcError> {
+        IpcError::from_status(Self::send_with_timeout(m, NON_BLOCKING))
+    }
+
+    /// Send a message, giving up after `ms` milliseconds
+    #[inline]
+    pub fn send_timeout(m: Message, ms: usize) -> Result<(), IpcError> {
+        IpcError::from_status(Self::send_with_timeout(m, ms))
+    }
+
+    #[inline]
+    pub fn receive(from: Option<TaskId>) -> Result<Message, IpcError> {
+        Self::receive_timeout(from, BLOCK_FOREVER)
+    }
+
+    /// Receive a message only if a sender is waiting
+    #[inline]
+    pub fn try_receive(from: Option<TaskId>) -> Result<Message, IpcError> {
+        Self::receive_timeout(from, NON_BLOCKING)
+    }
+
+    /// Receive a message, giving up after `ms` milliseconds
+    #[inline]
+    pub fn receive_timeout(from: Option<TaskId>, ms: usize) -> Result<Message, IpcError> {
+        let (ret, msg) = Self::receive_with_timeout(from, ms);
+        IpcError::from_status(ret).map(|_| msg)
+    }
+
+    /// Send a message to `m.receiver`, and wait for its reply
+    #[inline]
+    pub fn send_receive(m: Message) -> Result<Message, IpcError> {
+        Self::send_receive_timeout(m, BLOCK_FOREVER)
+    }
+
+    /// Send a message and wait for the reply, giving up after `ms` milliseconds
+    #[inline]
+    pub fn send_receive_timeout(m: Message, ms: usize) -> Result<Message, IpcError> {
+        let (ret, reply) = Self::send_receive_with_timeout(m, ms);
+        IpcError::from_status(ret).map(|_| reply)
+    }
+
+    /// Set the notification `bits` of `target`, without blocking.
+    /// The target gets them by receiving from any task.
+    #[inline]
+    pub fn notify(target: TaskId, bits: usize) -> Result<(), IpcError> {
+        let ret: isize;
+        unsafe {
+            llvm_asm!("svc #0":"={x0}"(ret):"{x0}"(Self::Notify as usize), "{x1}"(target.0), "{x2}"(bits): "x0" "x1" "x2" "memory");
+        }
+        IpcError::from_status(ret)
+    }
+
+    /// Let the scheduler switch to another ready task. Returns immediately if no other task is ready.
+    #[inline]
+    pub fn yield_now() {
+        unsafe {
+            llvm_asm!("svc #0"::"{x0}"(Self::Yield as usize): "x0" "memory");
+        }
+    }
+
+    #[inline]
+    fn send_with_timeout(mut m: Message, timeout: usize) -> isize {
         let ret: isize;
         unsafe {
-            llvm_asm!("svc #0":"={x0}"(ret):"{x0}"(Self::Send as usize), "{x1}"(&mut m as *mut Message): "x0" "x1" "memory");
+            llvm_asm!("svc #0":"={x0}"(ret):"{x0}"(Self::Send as usize), "{x1}"(&mut m as *mut Message), "{x2}"(timeout): "x0" "x1" "x2" "memory");
         }
-        assert!(ret == 0, "{:?}", ret);
+        ret
     }
 
     #[inline]
-    pub fn receive(from: Option<TaskId>) -> Message {
+    fn receive_with_timeout(from: Option<TaskId>, timeout: usize) -> (isize, Message) {
         unsafe {
             let mut msg: Message = ::core::mem::zeroed();
             let from_task: isize = match from {
@@ -40,9 +167,18 @@ impl IPC {
                 None => -1,
             };
             let ret: isize;
-            llvm_asm!("svc #0":"={x0}"(ret):"{x0}"(Self::Receive as usize), "{x1}"(from_task), "{x2}"(&mut msg as *mut Message):"x0" "x1" "x2" "memory");
-            assert!(ret == 0, "{:?}", ret);
-            msg
+            llvm_asm!("svc #0":"={x0}"(ret):"{x0}"(Self::Receive as usize), "{x1}"(from_task), "{x2}"(&mut msg as *mut Message), "{x3}"(timeout):"x0" "x1" "x2" "x3" "memory");
+            (ret, msg)
+        }
+    }
+
+    #[inline]
+    fn send_receive_with_timeout(mut m: Message, timeout: usize) -> (isize, Message) {
+        unsafe {
+            let mut reply: Message = ::core::mem::zeroed();
+            let ret: isize;
+            llvm_asm!("svc #0":"={x0}"(ret):"{x0}"(Self::SendReceive as usize), "{x1}"(&mut m as *mut Message), "{x2}"(&mut reply as *mut Message), "{x3}"(timeout):"x0" "x1" "x2" "x3" "memory");
+            (ret, reply)
         }
     }
 }
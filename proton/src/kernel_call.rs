@@ -1,15 +1,55 @@
 use crate::*;
 use super::page::{Page, Frame};
 use super::address::Address;
+use super::memory::PageFlags;
+use super::grant::{GrantId, GrantAccess};
 
 
+/// Why a task exited
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The task called `KernelCall::exit`
+    Exit(isize),
+    /// Unresolved page fault at `address`, caused by the instruction at `pc`
+    PageFault { address: Address, pc: Address },
+    /// Unhandled exception, with the raw exception syndrome
+    Exception { syndrome: usize, pc: Address },
+}
+
+/// Notification bit set on the exception handler, when a task is killed by an exception.
+/// See `KernelCall::set_exception_handler`.
+pub const EXCEPTION_NOTIFICATION: usize = 1 << 63;
+
+/// Reply of `KernelCall::GetTime`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Time {
+    /// Monotonic time since boot, in nanoseconds
+    pub nanos: u64,
+    /// Frequency of the system counter, in Hz
+    pub frequency: u64,
+}
 
 #[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KernelCall {
     Fork = 0,
     Exit,
     Sleep,
     MapPhysicalMemory,
+    MemoryMap,
+    MemoryUnmap,
+    SetExceptionHandler,
+    SetPriority,
+    GetTime,
+    Grant,
+    RevokeGrant,
+    SafeCopyFrom,
+    SafeCopyTo,
+    IrqSubscribe,
+    IrqAck,
+    TakeExceptionReport,
 
     #[allow(non_camel_case_types)]
     __MAX_COUNT,
@@ -21,8 +61,7 @@ impl KernelCall {
     #[inline]
     pub fn fork() -> Result<Option<TaskId>, ()> {
         let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::Fork as _);
-        message.send();
-        let reply = Message::receive(Some(TaskId::KERNEL));
+        let reply = message.send_receive().map_err(|_| ())?;
         let task_id = reply.get_data::<isize>();
         unsafe {
             if *task_id == 0 {
@@ -35,12 +74,20 @@ impl KernelCall {
         }
     }
 
+    #[inline]
+    pub fn exit(code: isize) -> ! {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::Exit as _)
+            .with_data(code);
+        // Block for a reply that never comes, so the task is not running when the kernel releases it
+        let _ = message.send_receive();
+        unreachable!()
+    }
+
     #[inline]
     pub fn map_physical_memory(page: Page, frame: Frame) -> Result<Page, ()> {
         let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::MapPhysicalMemory as _)
             .with_data((frame, page));
-        message.send();
-        let reply = Message::receive(Some(TaskId::KERNEL));
+        let reply = message.send_receive().map_err(|_| ())?;
         let addr = reply.get_data::<Address>();
         if addr.is_zero() || *addr != page.start() {
             // use super::log::log;
@@ -52,11 +99,179 @@ impl KernelCall {
         }
     }
 
+    /// Map zeroed anonymous memory. The kernel picks the address if `address` is `None`.
+    #[inline]
+    pub fn memory_map(address: Option<Address>, size: usize, flags: PageFlags) -> Result<Address, ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::MemoryMap as _)
+            .with_data((address.unwrap_or(Address::ZERO), size, flags));
+        let reply = message.send_receive().map_err(|_| ())?;
+        let addr = reply.get_data::<Address>();
+        if addr.is_zero() {
+            Err(())
+        } else {
+            Ok(*addr)
+        }
+    }
+
+    /// Unmap a region returned by `memory_map`
+    #[inline]
+    pub fn memory_unmap(address: Address, size: usize) -> Result<(), ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::MemoryUnmap as _)
+            .with_data((address, size));
+        let reply = message.send_receive().map_err(|_| ())?;
+        if *reply.get_data::<isize>() == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Register the calling task as the exception handler.
+    ///
+    /// When a task is killed by an exception, the handler is notified with `EXCEPTION_NOTIFICATION`.
+    /// The reports are then read by `take_exception_report`.
     #[inline]
-    pub fn sleep() -> Result<(), ()> {
-        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::Sleep as _);
-        message.send();
-        let _reply = Message::receive(Some(TaskId::KERNEL));
+    pub fn set_exception_handler() -> Result<(), ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::SetExceptionHandler as _);
+        let reply = message.send_receive().map_err(|_| ())?;
+        if *reply.get_data::<isize>() == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Take the oldest report of a task killed by an exception, or `None` if there are no more reports.
+    /// Only the exception handler can take the reports.
+    #[inline]
+    pub fn take_exception_report() -> Result<Option<(TaskId, ExitReason)>, ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::TakeExceptionReport as _);
+        let reply = message.send_receive().map_err(|_| ())?;
+        match *reply.get_data::<isize>() {
+            0 => {
+                let (_, task, reason) = *reply.get_data::<(isize, TaskId, ExitReason)>();
+                Ok(Some((task, reason)))
+            }
+            1 => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    /// Set the scheduling priority of a task, or the calling task if `task` is `None`.
+    ///
+    /// Without extra privileges, a task can only change its own priority,
+    /// and can't raise it above its current priority.
+    #[inline]
+    pub fn set_priority(task: Option<TaskId>, priority: Priority) -> Result<(), ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::SetPriority as _)
+            .with_data((task.unwrap_or(TaskId::NULL), priority));
+        let reply = message.send_receive().map_err(|_| ())?;
+        if *reply.get_data::<isize>() == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Block the calling task for at least `ms` milliseconds
+    #[inline]
+    pub fn sleep(ms: usize) -> Result<(), ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::Sleep as _)
+            .with_data(ms);
+        message.send_receive().map_err(|_| ())?;
         Ok(())
     }
+
+    /// Read the monotonic time since boot
+    #[inline]
+    pub fn get_time() -> Result<Time, ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::GetTime as _);
+        let reply = message.send_receive().map_err(|_| ())?;
+        let time = *reply.get_data::<Time>();
+        if time.frequency == 0 {
+            Err(())
+        } else {
+            Ok(time)
+        }
+    }
+
+    /// Allow `grantee` to copy from or to `size` bytes at `address`, in the calling task
+    #[inline]
+    pub fn grant(grantee: TaskId, address: Address, size: usize, access: GrantAccess) -> Result<GrantId, ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::Grant as _)
+            .with_data((grantee, address, size, access));
+        let reply = message.send_receive().map_err(|_| ())?;
+        let id = *reply.get_data::<isize>();
+        if id < 0 {
+            Err(())
+        } else {
+            Ok(GrantId(id as usize))
+        }
+    }
+
+    /// Revoke a grant created by the calling task
+    #[inline]
+    pub fn revoke_grant(grant: GrantId) -> Result<(), ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::RevokeGrant as _)
+            .with_data(grant);
+        let reply = message.send_receive().map_err(|_| ())?;
+        if *reply.get_data::<isize>() == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Copy `size` bytes at `offset` of a buffer granted by `granter`, to `address` in the calling task
+    #[inline]
+    pub fn safecopy_from(granter: TaskId, grant: GrantId, offset: usize, address: Address, size: usize) -> Result<(), ()> {
+        Self::safecopy(KernelCall::SafeCopyFrom, granter, grant, offset, address, size)
+    }
+
+    /// Copy `size` bytes at `address` in the calling task, to `offset` of a buffer granted by `granter`
+    #[inline]
+    pub fn safecopy_to(granter: TaskId, grant: GrantId, offset: usize, address: Address, size: usize) -> Result<(), ()> {
+        Self::safecopy(KernelCall::SafeCopyTo, granter, grant, offset, address, size)
+    }
+
+    #[inline]
+    fn safecopy(kind: KernelCall, granter: TaskId, grant: GrantId, offset: usize, address: Address, size: usize) -> Result<(), ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, kind as _)
+            .with_data((granter, grant, offset, address, size));
+        let reply = message.send_receive().map_err(|_| ())?;
+        if *reply.get_data::<isize>() == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Receive the device interrupt `irq` as notifications.
+    ///
+    /// When `irq` fires, it is masked and the calling task is notified with `bits`.
+    /// Call `irq_ack` to unmask it after the device is serviced.
+    #[inline]
+    pub fn irq_subscribe(irq: usize, bits: usize) -> Result<(), ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::IrqSubscribe as _)
+            .with_data((irq, bits));
+        let reply = message.send_receive().map_err(|_| ())?;
+        if *reply.get_data::<isize>() == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Unmask a device interrupt subscribed by the calling task
+    #[inline]
+    pub fn irq_ack(irq: usize) -> Result<(), ()> {
+        let message = Message::new(TaskId::NULL, TaskId::KERNEL, KernelCall::IrqAck as _)
+            .with_data(irq);
+        let reply = message.send_receive().map_err(|_| ())?;
+        if *reply.get_data::<isize>() == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
 }
@@ -20,6 +20,8 @@ pub mod ipc;
 mod address;
 mod page;
 pub mod memory;
+pub mod grant;
+pub mod esr;
 pub mod lazy;
 pub mod utils;
 
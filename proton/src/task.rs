@@ -1,4 +1,4 @@
-use crate::ipc::IPC;
+use crate::ipc::{IPC, IpcError, KIND_NOTIFICATION};
 
 #[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
 pub struct TaskId(pub usize);
@@ -8,8 +8,28 @@ impl TaskId {
     pub const KERNEL: Self = Self(0);
 }
 
+/// Scheduling priority. Tasks with larger values are scheduled first.
+#[repr(transparent)]
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct Priority(pub u8);
+
+impl Priority {
+    /// Number of priority levels
+    pub const LEVELS: usize = 8;
+    /// Only runs when nothing else is runnable. Never boosted by aging.
+    pub const IDLE: Self = Self(0);
+    pub const LOW: Self = Self(1);
+    pub const NORMAL: Self = Self(3);
+    pub const HIGH: Self = Self(5);
+    pub const HIGHEST: Self = Self(7);
+}
+
+impl Default for Priority {
+    fn default() -> Self { Self::NORMAL }
+}
+
 #[repr(C, align(64))]
-#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone)]
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
 pub struct Message {
     pub sender: TaskId,
     pub receiver: TaskId, // None for all tasks
@@ -45,18 +65,36 @@ impl Message {
     }
 
     #[inline]
-    pub fn send(self) {
-        IPC::send(self);
+    pub fn send(self) -> Result<(), IpcError> {
+        IPC::send(self)
+    }
+
+    /// Send only if the receiver is waiting for this message, see `IPC::try_send`
+    #[inline]
+    pub fn try_send(self) -> Result<(), IpcError> {
+        IPC::try_send(self)
     }
 
     #[inline]
-    pub fn receive(src: Option<TaskId>) -> Message {
+    pub fn receive(src: Option<TaskId>) -> Result<Message, IpcError> {
         IPC::receive(src)
     }
 
+    /// Whether this is a notification, see `IPC::notify`
+    #[inline]
+    pub fn is_notification(&self) -> bool {
+        self.sender == TaskId::KERNEL && self.kind == KIND_NOTIFICATION
+    }
+
+    /// Send this message, and wait for the reply from the receiver
+    #[inline]
+    pub fn send_receive(self) -> Result<Message, IpcError> {
+        IPC::send_receive(self)
+    }
+
     #[inline]
-    pub fn reply<T>(&self, data: T) {
+    pub fn reply<T>(&self, data: T) -> Result<(), IpcError> {
         let n = Message::new(self.receiver, self.sender, self.kind).with_data(data);
-        IPC::send(n);
+        IPC::send(n)
     }
 }
\ No newline at end of file
@@ -0,0 +1,176 @@
+use crate::address::*;
+use crate::page::*;
+use super::FrameAllocator;
+
+/// Max number of 4K frames managed by one allocator (256 MB)
+const MAX_FRAMES: usize = 1 << 16;
+const WORDS: usize = MAX_FRAMES / 64;
+
+/// A frame allocator that keeps one bit per 4K frame, so freed frames can be reused.
+pub struct BitMapFrameAllocator {
+    start: Address<P>,
+    limit: Address<P>,
+    /// Frame index to start the next search from
+    cursor: usize,
+    bitmap: [u64; WORDS],
+}
+
+impl BitMapFrameAllocator {
+    pub const fn new((start, limit): (Address<P>, Address<P>)) -> Self {
+        Self {
+            start, limit,
+            cursor: 0,
+            bitmap: [0; WORDS],
+        }
+    }
+
+    #[inline]
+    fn frames(&self) -> usize {
+        let frames = (self.limit - self.start) >> Size4K::LOG_SIZE;
+        assert!(frames <= MAX_FRAMES);
+        frames
+    }
+
+    #[inline]
+    fn index_of<S: PageSize>(&self, frame: Frame<S>) -> Option<usize> {
+        let a = frame.start();
+        if a < self.start || a >= self.limit {
+            None
+        } else {
+            Some((a - self.start) >> Size4K::LOG_SIZE)
+        }
+    }
+
+    #[inline]
+    fn is_used(&self, i: usize) -> bool {
+        self.bitmap[i >> 6] & (1 << (i & 63)) != 0
+    }
+
+    #[inline]
+    fn set_used(&mut self, i: usize, used: bool) {
+        if used {
+            self.bitmap[i >> 6] |= 1 << (i & 63);
+        } else {
+            self.bitmap[i >> 6] &= !(1 << (i & 63));
+        }
+    }
+
+    fn is_free_range(&self, start: usize, count: usize) -> bool {
+        (start..start + count).all(|i| !self.is_used(i))
+    }
+}
+
+impl FrameAllocator for BitMapFrameAllocator {
+    fn identity_alloc<S: PageSize>(&mut self, frame: Frame<S>) {
+        let count = Frame::<S>::SIZE >> Size4K::LOG_SIZE;
+        let frames = self.frames();
+        if let Some(start) = self.index_of(frame) {
+            for i in start..usize::min(start + count, frames) {
+                self.set_used(i, true);
+            }
+        }
+    }
+
+    fn alloc<S: PageSize>(&mut self) -> Frame<S> {
+        let count = Frame::<S>::SIZE >> Size4K::LOG_SIZE;
+        let frames = self.frames();
+        // Index of the first properly aligned frame
+        let first = (Frame::<S>::align_up(self.start) - self.start) >> Size4K::LOG_SIZE;
+        let hint = first + ((usize::max(self.cursor, first) - first) & !(count - 1));
+        let candidates = (hint..frames).step_by(count).chain((first..hint).step_by(count));
+        for i in candidates {
+            if i + count <= frames && self.is_free_range(i, count) {
+                for j in i..i + count {
+                    self.set_used(j, true);
+                }
+                self.cursor = i + count;
+                return Frame::new(self.start + (i << Size4K::LOG_SIZE));
+            }
+        }
+        panic!("Out of physical memory");
+    }
+
+    fn free<S: PageSize>(&mut self, frame: Frame<S>) {
+        let count = Frame::<S>::SIZE >> Size4K::LOG_SIZE;
+        if let Some(start) = self.index_of(frame) {
+            for i in start..start + count {
+                debug_assert!(self.is_used(i), "{:?} is not allocated", frame);
+                self.set_used(i, false);
+            }
+            if start < self.cursor {
+                self.cursor = start;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: usize = 0x4000_0000;
+
+    fn allocator(frames: usize) -> BitMapFrameAllocator {
+        BitMapFrameAllocator::new((Address::new(START), Address::new(START + (frames << Size4K::LOG_SIZE))))
+    }
+
+    fn frame<S: PageSize>(i: usize) -> Frame<S> {
+        Frame::new(Address::new(START + (i << Size4K::LOG_SIZE)))
+    }
+
+    #[test]
+    fn alloc_until_exhaustion() {
+        let mut fa = allocator(100);
+        for i in 0..100 {
+            assert_eq!(fa.alloc::<Size4K>(), frame(i));
+        }
+        assert!((0..100).all(|i| fa.is_used(i)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Out of physical memory")]
+    fn alloc_after_exhaustion() {
+        let mut fa = allocator(100);
+        for _ in 0..100 {
+            fa.alloc::<Size4K>();
+        }
+        fa.alloc::<Size4K>();
+    }
+
+    #[test]
+    fn free_and_reuse() {
+        let mut fa = allocator(100);
+        for _ in 0..100 {
+            fa.alloc::<Size4K>();
+        }
+        fa.free(frame::<Size4K>(70));
+        fa.free(frame::<Size4K>(3));
+        assert!(!fa.is_used(3) && !fa.is_used(70));
+        assert_eq!(fa.alloc::<Size4K>(), frame(3));
+        assert_eq!(fa.alloc::<Size4K>(), frame(70));
+        assert!((0..100).all(|i| fa.is_used(i)));
+    }
+
+    #[test]
+    fn multi_frame_alloc_across_words() {
+        let mut fa = allocator(2048);
+        // A used 4K frame in the second word makes the first 2M range unavailable
+        fa.identity_alloc(frame::<Size4K>(100));
+        let huge = fa.alloc::<Size2M>();
+        assert_eq!(huge, frame(512));
+        assert!((512..1024).all(|i| fa.is_used(i)));
+        assert!(!fa.is_used(511) && !fa.is_used(1024));
+        fa.free(huge);
+        assert!(fa.is_free_range(512, 512));
+        assert_eq!(fa.alloc::<Size2M>(), frame(512));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not allocated")]
+    fn double_free() {
+        let mut fa = allocator(100);
+        let f = fa.alloc::<Size4K>();
+        fa.free(f);
+        fa.free(f);
+    }
+}
@@ -32,4 +32,5 @@ impl <FA: FrameAllocator> SynchronizedFrameAllocator<FA> {
     }
 }
 
-pub mod bump_allocator;
\ No newline at end of file
+pub mod bump_allocator;
+pub mod bitmap_allocator;